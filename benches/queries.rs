@@ -0,0 +1,97 @@
+//! Time-range queries and the per-file lookups behind live playlists, against a year of
+//! 15-second chunks from one camera, about 2M rows.
+//!
+//! Run with `cargo bench --bench queries`. Filling the database takes a while, the queries
+//! themselves should each take well under a millisecond.
+
+use std::str::FromStr;
+
+use camerars::db::Database;
+use camerars::playlist::PlaylistFile;
+use chrono::{DateTime, TimeDelta, Utc};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const CAMERA: &str = "porch";
+const CHUNK_SECONDS: i64 = 15;
+const CHUNKS: i64 = 365 * 24 * 60 * 60 / CHUNK_SECONDS;
+
+fn year_of_chunks(start: DateTime<Utc>) -> Database {
+    let db = Database::memory();
+    for seq_num in 0..CHUNKS {
+        let start_time = start + TimeDelta::seconds(seq_num * CHUNK_SECONDS);
+        // Leave out an hour every day, as if the camera went offline.
+        if seq_num % (24 * 60 * 60 / CHUNK_SECONDS) < 60 * 60 / CHUNK_SECONDS {
+            if seq_num % (24 * 60 * 60 / CHUNK_SECONDS) == 0 {
+                db.append_gap(CAMERA, start_time, start_time + TimeDelta::hours(1))
+                    .unwrap();
+            }
+            continue;
+        }
+
+        let file = PlaylistFile {
+            duration: CHUNK_SECONDS as f64,
+            id: format!("{seq_num:0>9}.ts"),
+            init: None,
+            media_start: None,
+        };
+        db.append_file(CAMERA, start_time, file).unwrap();
+    }
+
+    db
+}
+
+fn queries(c: &mut Criterion) {
+    let start = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+    let db = year_of_chunks(start);
+
+    // Mid-year, and off the chunk boundaries so the first chunk starts before the range.
+    let from = start + TimeDelta::days(180) + TimeDelta::hours(12) + TimeDelta::seconds(7);
+
+    c.bench_function("query_files/hour", |b| {
+        b.iter(|| {
+            let files = db
+                .query_files(CAMERA, Some(from), Some(from + TimeDelta::hours(1)))
+                .unwrap();
+            assert_eq!(files.len(), 241);
+            black_box(files)
+        })
+    });
+    c.bench_function("query_files/day", |b| {
+        b.iter(|| {
+            black_box(
+                db.query_files(CAMERA, Some(from), Some(from + TimeDelta::days(1)))
+                    .unwrap(),
+            )
+        })
+    });
+    c.bench_function("query_latest_files", |b| {
+        b.iter(|| black_box(db.query_latest_files(CAMERA, 5).unwrap()))
+    });
+    c.bench_function("query_overlapping_gaps/day", |b| {
+        b.iter(|| {
+            black_box(
+                db.query_overlapping_gaps(CAMERA, from, from + TimeDelta::days(1))
+                    .unwrap(),
+            )
+        })
+    });
+
+    // A live playlist looks up the sequence numbers of the first file it lists.
+    let first = db
+        .query_timed_files(CAMERA, Some(from), Some(from + TimeDelta::minutes(1)))
+        .unwrap()
+        .remove(0);
+    c.bench_function("media_sequence", |b| {
+        b.iter(|| black_box(db.media_sequence(CAMERA, &first).unwrap()))
+    });
+    c.bench_function("discontinuity_sequence", |b| {
+        b.iter(|| black_box(db.discontinuity_sequence(CAMERA, &first).unwrap()))
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = queries
+}
+criterion_main!(benches);
@@ -0,0 +1,105 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Exponential backoff, doubling the delay after every failed attempt up to a ceiling.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Delay to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = self.current.saturating_mul(2).min(self.max);
+
+        delay
+    }
+
+    /// Start over from the initial delay, e.g. after a successful attempt.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    /// Delay before retrying after `failures` consecutive failed attempts, for callers that keep
+    /// track of the attempt count themselves.
+    pub fn delay_after(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Randomize a delay to somewhere between half of it and all of it, so that retries that failed
+/// together don't all come back at the same moment.
+pub fn jitter(delay: Duration) -> Duration {
+    // RandomState is seeded randomly, which is all the randomness we need here.
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = 0.5 + (random % 1_000) as f64 / 2_000.0;
+
+    delay.mul_f64(fraction)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::backoff::{jitter, Backoff};
+
+    #[test]
+    pub fn test_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+    }
+
+    #[test]
+    pub fn test_delay_after() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        assert_eq!(backoff.delay_after(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay_after(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay_after(3), Duration::from_secs(4));
+        assert_eq!(backoff.delay_after(50), Duration::from_secs(5));
+    }
+
+    #[test]
+    pub fn test_jitter() {
+        for _ in 0..100 {
+            let delay = jitter(Duration::from_secs(10));
+            assert!(delay >= Duration::from_secs(5));
+            assert!(delay <= Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    pub fn test_reset() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
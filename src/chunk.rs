@@ -1,13 +1,77 @@
 use ffmpeg_next::codec::Parameters;
 use ffmpeg_next::{Dictionary, Packet, Rational};
 
+use std::path::{Path, PathBuf};
+
 pub mod file;
+pub mod fmp4;
+pub(crate) mod quota;
+
+/// Container format chunks are recorded in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkFormat {
+    /// MPEG-TS, written by [`file::FileChunkWriter`].
+    #[default]
+    Ts,
+    /// Fragmented MP4, written by [`fmp4::FragmentedMp4ChunkWriter`].
+    Fmp4,
+}
+
+/// Whether `path` looks like a chunk written by one of the [`ChunkWriter`]s.
+pub fn is_chunk(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "ts" || extension == "m4s")
+}
+
+/// Whether `path` looks like an init segment shared by fragmented MP4 chunks.
+pub fn is_init_segment(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension == "mp4")
+        && path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("init-"))
+}
+
+/// Sequence number of the chunk named `file_id`, which stays the same across restarts and as
+/// older chunks are pruned.
+pub fn seq_num(file_id: &str) -> Option<u64> {
+    let path = Path::new(file_id);
+    if !is_chunk(path) {
+        return None;
+    }
+
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Highest sequence number of the chunks with `extension` in `directory`, or 0 if there are none.
+pub(crate) fn last_seq_num(directory: &Path, extension: &str) -> u64 {
+    let suffix = format!(".{extension}");
+
+    std::fs::read_dir(directory)
+        .expect("directory created in init")
+        .filter_map(|dirent| {
+            let dirent = dirent.expect("dirent readable");
+            if !dirent.file_type().expect("file_type").is_file() {
+                None
+            } else {
+                dirent
+                    .file_name()
+                    .to_string_lossy()
+                    .strip_suffix(&suffix)
+                    .and_then(|num| num.parse().ok())
+            }
+        })
+        .max()
+        .unwrap_or_default()
+}
 
 mod private {
     pub trait Sealed {}
 
     // Impls
     impl Sealed for crate::chunk::file::FileChunkWriter {}
+    impl Sealed for crate::chunk::fmp4::FragmentedMp4ChunkWriter {}
 }
 
 pub trait ChunkWriter: private::Sealed {
@@ -28,7 +92,13 @@ pub trait ChunkWriter: private::Sealed {
     fn write_audio(&mut self, packet: Packet, src_timebase: Rational);
 
     /// Close any resources associated with this chunk.
-    fn end(&mut self) -> std::path::PathBuf;
+    fn end(&mut self) -> PathBuf;
+
+    /// Init segment the chunk depends on, for formats that keep it separately. Only known once
+    /// the chunk has ended.
+    fn init_segment(&self) -> Option<PathBuf> {
+        None
+    }
 }
 
 pub trait ChunkWriterFactory {
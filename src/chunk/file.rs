@@ -5,11 +5,14 @@ use ffmpeg_next::format::context::Output;
 use ffmpeg_next::{codec, encoder, format, Dictionary, Packet, Rational};
 use tracing::{debug, info};
 
-use crate::chunk::{ChunkWriter, ChunkWriterFactory};
+use crate::chunk::quota::DiskQuota;
+use crate::chunk::{last_seq_num, ChunkWriter, ChunkWriterFactory};
+use crate::db::Database;
 
 pub struct FileChunkWriterFactory {
     directory: PathBuf,
     seq_num: u64,
+    quota: Option<DiskQuota>,
 }
 
 impl FileChunkWriterFactory {
@@ -17,8 +20,17 @@ impl FileChunkWriterFactory {
         Self {
             seq_num: 0,
             directory: path.as_ref().to_path_buf(),
+            quota: None,
         }
     }
+
+    /// Before opening each chunk, evict the oldest uploaded chunks of `camera_id` while the
+    /// directory holds more than `max_bytes`.
+    pub fn with_quota(mut self, camera_id: &str, db: &Database, max_bytes: u64) -> Self {
+        self.quota = Some(DiskQuota::new(camera_id, db, max_bytes));
+
+        self
+    }
 }
 
 impl ChunkWriterFactory for FileChunkWriterFactory {
@@ -29,27 +41,15 @@ impl ChunkWriterFactory for FileChunkWriterFactory {
         std::fs::create_dir_all(&self.directory).ok();
 
         // Find the last sequence number
-        self.seq_num = std::fs::read_dir(&self.directory)
-            .expect("directory created in init")
-            .filter_map(|dirent| {
-                let dirent = dirent.expect("dirent readable");
-                if !dirent.file_type().expect("file_type").is_file() {
-                    None
-                } else {
-                    dirent
-                        .file_name()
-                        .to_string_lossy()
-                        .strip_suffix(".ts")
-                        .and_then(|num| num.parse().ok())
-                }
-            })
-            .max()
-            .unwrap_or_default();
+        self.seq_num = last_seq_num(&self.directory, "ts");
 
         info!("initializing with seq_num {}", self.seq_num);
     }
 
     fn next(&mut self) -> Self::Target {
+        if let Some(quota) = &self.quota {
+            quota.enforce(&self.directory);
+        }
         self.seq_num += 1;
 
         FileChunkWriter::new(
@@ -84,26 +84,12 @@ impl ChunkWriter for FileChunkWriter {
         audio_parameters: Option<Parameters>,
     ) {
         debug!("initializing video stream");
-        let mut ost = self
-            .ctx
-            .add_stream(encoder::find(codec::Id::None))
-            .expect("add_stream for video should succeed");
-        ost.set_parameters(video_parameters);
-        unsafe {
-            (*ost.parameters().as_mut_ptr()).codec_tag = 0;
-        }
+        add_stream(&mut self.ctx, video_parameters, 0);
 
         // Optionally, initialize audio stream
         if let Some(audio_parameters) = audio_parameters {
             debug!("initializing audio stream");
-            let mut ost = self
-                .ctx
-                .add_stream(encoder::find(codec::Id::None))
-                .expect("add_stream for audio should succeed");
-            ost.set_parameters(audio_parameters);
-            unsafe {
-                (*ost.parameters().as_mut_ptr()).codec_tag = 0;
-            }
+            add_stream(&mut self.ctx, audio_parameters, 0);
         }
 
         self.ctx.set_metadata(metadata.clone());
@@ -113,36 +99,19 @@ impl ChunkWriter for FileChunkWriter {
     }
 
     fn video_timebase(&self) -> Rational {
-        self.ctx
-            .stream(0)
-            .expect("stream 0 should exist and be the video stream")
-            .time_base()
+        stream_timebase(&self.ctx, 0).expect("stream 0 should exist and be the video stream")
     }
 
     fn audio_timebase(&self) -> Option<Rational> {
-        self.ctx.stream(1).map(|stream| stream.time_base())
+        stream_timebase(&self.ctx, 1)
     }
 
-    fn write_video(&mut self, mut packet: Packet, src_timebase: Rational) {
-        packet.rescale_ts(src_timebase, self.video_timebase());
-        packet.set_position(-1);
-        packet.set_stream(0);
-        packet
-            .write_interleaved(&mut self.ctx)
-            .expect("expected write_interleaved for video to succeed");
+    fn write_video(&mut self, packet: Packet, src_timebase: Rational) {
+        write_packet(&mut self.ctx, packet, src_timebase, 0);
     }
 
-    fn write_audio(&mut self, mut packet: Packet, src_timebase: Rational) {
-        packet.rescale_ts(
-            src_timebase,
-            self.audio_timebase()
-                .expect("audio_timebase should be non-None"),
-        );
-        packet.set_position(-1);
-        packet.set_stream(1);
-        packet
-            .write_interleaved(&mut self.ctx)
-            .expect("expected write_interleaved for audio to succeed");
+    fn write_audio(&mut self, packet: Packet, src_timebase: Rational) {
+        write_packet(&mut self.ctx, packet, src_timebase, 1);
     }
 
     fn end(&mut self) -> PathBuf {
@@ -153,3 +122,35 @@ impl ChunkWriter for FileChunkWriter {
         self.path.clone()
     }
 }
+
+/// Add a stream copying `parameters` to the output. A `codec_tag` of 0 lets the muxer pick one.
+pub(crate) fn add_stream(ctx: &mut Output, parameters: Parameters, codec_tag: u32) {
+    let mut ost = ctx
+        .add_stream(encoder::find(codec::Id::None))
+        .expect("add_stream should succeed");
+    ost.set_parameters(parameters);
+    unsafe {
+        (*ost.parameters().as_mut_ptr()).codec_tag = codec_tag;
+    }
+}
+
+/// Time base of the output stream at `index`, if the output has that many streams.
+pub(crate) fn stream_timebase(ctx: &Output, index: usize) -> Option<Rational> {
+    ctx.stream(index).map(|stream| stream.time_base())
+}
+
+/// Write `packet`, timestamped in `src_timebase`, to the output stream at `index`.
+pub(crate) fn write_packet(
+    ctx: &mut Output,
+    mut packet: Packet,
+    src_timebase: Rational,
+    index: usize,
+) {
+    let timebase = stream_timebase(ctx, index).expect("output stream should exist");
+    packet.rescale_ts(src_timebase, timebase);
+    packet.set_position(-1);
+    packet.set_stream(index);
+    packet
+        .write_interleaved(ctx)
+        .expect("expected write_interleaved to succeed");
+}
@@ -0,0 +1,308 @@
+//! Fragmented MP4 (CMAF) chunks.
+//!
+//! Every chunk is written as its own fragmented MP4 file, which is then split into the `.m4s`
+//! media segment and the init segment (`ftyp` + `moov`) that it starts with. Init segments are
+//! named after a hash of their contents, so all chunks of a session share one, and a new one
+//! only appears when the stream parameters change.
+
+use std::path::{Path, PathBuf};
+
+use ffmpeg_next::codec::Parameters;
+use ffmpeg_next::format::context::Output;
+use ffmpeg_next::{codec, format, Dictionary, Packet, Rational};
+use tracing::{debug, info, warn};
+
+use crate::chunk::file::{add_stream, stream_timebase, write_packet};
+use crate::chunk::quota::DiskQuota;
+use crate::chunk::{last_seq_num, ChunkWriter, ChunkWriterFactory};
+use crate::db::Database;
+
+/// Fragment at every keyframe, keep the `moov` free of samples so it can serve as init segment,
+/// and keep absolute decode times in `tfdt` so segments from separate files line up.
+const MOVFLAGS: &str = "frag_keyframe+empty_moov+default_base_moof+frag_discont+skip_trailer";
+
+pub struct FragmentedMp4ChunkWriterFactory {
+    directory: PathBuf,
+    seq_num: u64,
+    quota: Option<DiskQuota>,
+}
+
+impl FragmentedMp4ChunkWriterFactory {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            seq_num: 0,
+            directory: path.as_ref().to_path_buf(),
+            quota: None,
+        }
+    }
+
+    /// Before opening each chunk, evict the oldest uploaded chunks of `camera_id` while the
+    /// directory holds more than `max_bytes`.
+    pub fn with_quota(mut self, camera_id: &str, db: &Database, max_bytes: u64) -> Self {
+        self.quota = Some(DiskQuota::new(camera_id, db, max_bytes));
+
+        self
+    }
+}
+
+impl ChunkWriterFactory for FragmentedMp4ChunkWriterFactory {
+    type Target = FragmentedMp4ChunkWriter;
+
+    fn init(&mut self) {
+        std::fs::create_dir_all(&self.directory).ok();
+
+        self.seq_num = last_seq_num(&self.directory, "m4s");
+
+        info!("initializing with seq_num {}", self.seq_num);
+    }
+
+    fn next(&mut self) -> Self::Target {
+        if let Some(quota) = &self.quota {
+            quota.enforce(&self.directory);
+        }
+        self.seq_num += 1;
+
+        FragmentedMp4ChunkWriter::new(&self.directory.join(format!("{:0>9}.m4s", self.seq_num)))
+    }
+}
+
+pub struct FragmentedMp4ChunkWriter {
+    ctx: Output,
+    path: PathBuf,
+    init_segment: Option<PathBuf>,
+}
+
+impl FragmentedMp4ChunkWriter {
+    pub fn new<P: AsRef<Path>>(path: &P) -> Self {
+        let ctx = format::output_as(path, "mp4").expect("creating output context should succeed");
+        let path = path.as_ref().into();
+
+        Self {
+            ctx,
+            path,
+            init_segment: None,
+        }
+    }
+
+    /// Move the init segment out of the finished chunk into its own file, returning its path.
+    fn split_init_segment(&self) -> std::io::Result<PathBuf> {
+        let data = std::fs::read(&self.path)?;
+        let (init, media) = split_segment(&data).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "chunk is not a fragmented MP4",
+            )
+        })?;
+
+        let init_path = self.path.with_file_name(init_segment_name(init));
+        if !init_path.exists() {
+            debug!(path = ?init_path, "writing new init segment");
+            replace_file(&init_path, init)?;
+        }
+
+        replace_file(&self.path, &media)?;
+
+        Ok(init_path)
+    }
+}
+
+impl ChunkWriter for FragmentedMp4ChunkWriter {
+    fn begin(
+        &mut self,
+        metadata: &Dictionary,
+        video_parameters: Parameters,
+        audio_parameters: Option<Parameters>,
+    ) {
+        // Safari only plays HEVC tagged as `hvc1`, not the `hev1` the muxer would pick.
+        let video_tag = if video_parameters.id() == codec::Id::HEVC {
+            u32::from_le_bytes(*b"hvc1")
+        } else {
+            0
+        };
+
+        debug!("initializing video stream");
+        add_stream(&mut self.ctx, video_parameters, video_tag);
+
+        if let Some(audio_parameters) = audio_parameters {
+            debug!("initializing audio stream");
+            add_stream(&mut self.ctx, audio_parameters, 0);
+        }
+
+        let mut options = Dictionary::new();
+        options.set("movflags", MOVFLAGS);
+
+        self.ctx.set_metadata(metadata.clone());
+        self.ctx
+            .write_header_with(options)
+            .expect("writing output header should succeed");
+    }
+
+    fn video_timebase(&self) -> Rational {
+        stream_timebase(&self.ctx, 0).expect("stream 0 should exist and be the video stream")
+    }
+
+    fn audio_timebase(&self) -> Option<Rational> {
+        stream_timebase(&self.ctx, 1)
+    }
+
+    fn write_video(&mut self, packet: Packet, src_timebase: Rational) {
+        write_packet(&mut self.ctx, packet, src_timebase, 0);
+    }
+
+    fn write_audio(&mut self, packet: Packet, src_timebase: Rational) {
+        write_packet(&mut self.ctx, packet, src_timebase, 1);
+    }
+
+    fn end(&mut self) -> PathBuf {
+        self.ctx
+            .write_trailer()
+            .expect("writing output trailer should succeed");
+
+        match self.split_init_segment() {
+            Ok(init_segment) => self.init_segment = Some(init_segment),
+            Err(e) => warn!(error = %e, path = ?self.path, "failed to split off init segment"),
+        }
+
+        self.path.clone()
+    }
+
+    fn init_segment(&self) -> Option<PathBuf> {
+        self.init_segment.clone()
+    }
+}
+
+/// Write `data` to `path` through a temporary file, so a crash halfway through leaves either the
+/// old contents or the new ones. The temporary file's extension keeps it from passing for a
+/// chunk or an init segment.
+fn replace_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// File name of the init segment with the given contents. The hash must stay the same across
+/// builds and toolchains, or a restart would start a new init segment for unchanged parameters.
+fn init_segment_name(init: &[u8]) -> String {
+    // 64-bit FNV-1a.
+    let hash = init.iter().fold(0xcbf29ce484222325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    format!("init-{hash:016x}.mp4")
+}
+
+/// Split a fragmented MP4 file into its init segment, everything before the first `moof` box,
+/// and the media segment after it. Returns `None` if the file has no fragments or is malformed.
+fn split_segment(data: &[u8]) -> Option<(&[u8], Vec<u8>)> {
+    let mut init_end = None;
+    let mut media = Vec::new();
+
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data.get(offset..offset + 8)?;
+        let box_type = &header[4..8];
+        let size = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            0 => data.len() - offset,
+            1 => {
+                let large_size = data.get(offset + 8..offset + 16)?;
+                usize::try_from(u64::from_be_bytes(large_size.try_into().unwrap())).ok()?
+            }
+            size => size as usize,
+        };
+        // A 64-bit size can be large enough to wrap around.
+        let end = offset.checked_add(size)?;
+        if size < 8 || end > data.len() {
+            return None;
+        }
+
+        if box_type == b"moof" && init_end.is_none() {
+            init_end = Some(offset);
+        }
+        // The random access index at the end refers to the whole file, which no longer exists.
+        if init_end.is_some() && box_type != b"mfra" {
+            media.extend_from_slice(&data[offset..end]);
+        }
+
+        offset = end;
+    }
+
+    Some((&data[..init_end?], media))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::chunk::fmp4::{init_segment_name, replace_file, split_segment};
+    use crate::test_util::scratch_dir;
+
+    fn mp4_box(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(box_type);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    pub fn test_split_segment() {
+        let init = [mp4_box(b"ftyp", b"iso5"), mp4_box(b"moov", b"trak")].concat();
+        let fragments = [
+            mp4_box(b"moof", b"1"),
+            mp4_box(b"mdat", b"frames"),
+            mp4_box(b"moof", b"2"),
+            mp4_box(b"mdat", b"more frames"),
+        ]
+        .concat();
+        let file = [init.clone(), fragments.clone(), mp4_box(b"mfra", b"index")].concat();
+
+        let (split_init, media) = split_segment(&file).unwrap();
+        assert_eq!(split_init, init);
+        assert_eq!(media, fragments);
+    }
+
+    #[test]
+    pub fn test_split_segment_large_size() {
+        // A `largesize` box claiming to run to the end of the address space.
+        let mut huge = 1_u32.to_be_bytes().to_vec();
+        huge.extend_from_slice(b"free");
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        let file = [mp4_box(b"ftyp", b"iso5"), huge].concat();
+
+        assert_eq!(split_segment(&file), None);
+    }
+
+    #[test]
+    pub fn test_replace_file() {
+        let scratch = scratch_dir();
+        let path = scratch.path().join("000000001.m4s");
+        std::fs::write(&path, b"whole chunk").unwrap();
+
+        replace_file(&path, b"media").unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"media");
+        let names: Vec<_> = std::fs::read_dir(scratch.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["000000001.m4s"]);
+    }
+
+    #[test]
+    pub fn test_init_segment_name() {
+        assert_eq!(init_segment_name(b""), "init-cbf29ce484222325.mp4");
+        assert_eq!(init_segment_name(b"a"), "init-af63dc4c8601ec8c.mp4");
+    }
+
+    #[test]
+    pub fn test_split_segment_invalid() {
+        // No fragments at all.
+        let file = [mp4_box(b"ftyp", b"iso5"), mp4_box(b"moov", b"trak")].concat();
+        assert!(split_segment(&file).is_none());
+
+        // Truncated box.
+        let mut file = [mp4_box(b"ftyp", b"iso5"), mp4_box(b"moof", b"1")].concat();
+        file.truncate(file.len() - 1);
+        assert!(split_segment(&file).is_none());
+    }
+}
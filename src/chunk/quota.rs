@@ -0,0 +1,175 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, warn};
+
+use crate::chunk::is_chunk;
+use crate::db::Database;
+use crate::upload::queue::UploadStatus;
+
+/// Limit on the bytes taken up by chunks in a directory. Only chunks that were confirmed
+/// uploaded can be evicted to make room. Chunks whose remote copy was already pruned are
+/// forgotten once evicted, since nothing is left of them.
+pub(crate) struct DiskQuota {
+    camera_id: String,
+    db: Database,
+    max_bytes: u64,
+}
+
+impl DiskQuota {
+    pub(crate) fn new(camera_id: &str, db: &Database, max_bytes: u64) -> Self {
+        Self {
+            camera_id: camera_id.to_string(),
+            db: db.clone(),
+            max_bytes,
+        }
+    }
+
+    /// Evict the oldest uploaded chunks from `directory` until it's back under the quota.
+    pub(crate) fn enforce(&self, directory: &Path) {
+        let Ok(entries) = std::fs::read_dir(directory) else {
+            return;
+        };
+        let mut chunks: Vec<(PathBuf, u64)> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                (metadata.is_file() && is_chunk(&entry.path()))
+                    .then(|| (entry.path(), metadata.len()))
+            })
+            .collect();
+        // Chunk names are zero-padded sequence numbers, so this is oldest first.
+        chunks.sort();
+
+        let mut used: u64 = chunks.iter().map(|(_, size)| size).sum();
+        if used <= self.max_bytes {
+            return;
+        }
+
+        // Never evict the newest chunk, numbering continues from it after a restart.
+        chunks.pop();
+        for (path, size) in chunks {
+            if used <= self.max_bytes {
+                break;
+            }
+
+            let Some(file_id) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            // Chunks that can't be confirmed uploaded stay, even if the database is unavailable.
+            let pruned = match self.db.upload_status(&self.camera_id, file_id) {
+                Ok(Some(UploadStatus::Done)) => false,
+                Ok(Some(UploadStatus::Pruned)) => true,
+                _ => continue,
+            };
+
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    debug!(file = file_id, "evicted uploaded chunk from disk");
+                    used -= size;
+                    if pruned {
+                        if let Err(e) = self
+                            .db
+                            .remove_files(&self.camera_id, &[file_id.to_string()])
+                        {
+                            warn!(error = %e, file = file_id, "failed to forget evicted chunk");
+                        }
+                    }
+                }
+                Err(e) => warn!(error = %e, file = file_id, "failed to evict chunk"),
+            }
+        }
+
+        if used > self.max_bytes {
+            warn!(
+                camera = self.camera_id,
+                used,
+                max_bytes = self.max_bytes,
+                "disk quota exceeded, but the remaining chunks haven't been uploaded yet"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use crate::chunk::quota::DiskQuota;
+    use crate::db::Database;
+    use crate::playlist::PlaylistFile;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    pub fn test_quota() {
+        const CAMERA: &str = "default";
+
+        let scratch = scratch_dir();
+        let directory = scratch.path();
+
+        let db = Database::memory();
+        for name in [
+            "000000001.ts",
+            "000000002.ts",
+            "000000003.ts",
+            "000000004.ts",
+        ] {
+            let path = directory.join(name);
+            std::fs::write(&path, [0; 10]).unwrap();
+            db.enqueue_upload(CAMERA, name, &path, Utc::now()).unwrap();
+        }
+        // The second chunk hasn't been uploaded yet, and the newest one is kept regardless.
+        for name in ["000000001.ts", "000000003.ts", "000000004.ts"] {
+            db.complete_upload(CAMERA, name).unwrap();
+        }
+
+        DiskQuota::new(CAMERA, &db, 15).enforce(directory);
+
+        let mut remaining: Vec<_> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["000000002.ts", "000000004.ts"]);
+    }
+
+    #[test]
+    pub fn test_quota_pruned() {
+        const CAMERA: &str = "default";
+
+        let scratch = scratch_dir();
+        let directory = scratch.path();
+
+        let db = Database::memory();
+        for name in ["000000001.ts", "000000002.ts", "000000003.ts"] {
+            let path = directory.join(name);
+            std::fs::write(&path, [0; 10]).unwrap();
+            db.append_file(
+                CAMERA,
+                Utc::now(),
+                PlaylistFile {
+                    id: name.to_string(),
+                    duration: 15.0,
+                    init: None,
+                    media_start: None,
+                },
+            )
+            .unwrap();
+            db.enqueue_upload(CAMERA, name, &path, Utc::now()).unwrap();
+            db.complete_upload(CAMERA, name).unwrap();
+        }
+        // Retention already deleted the oldest chunk's remote copy.
+        db.prune_upload(CAMERA, "000000001.ts").unwrap();
+
+        DiskQuota::new(CAMERA, &db, 10).enforce(directory);
+
+        // Both evicted chunks are gone from disk, but only the pruned one is gone everywhere.
+        let files: Vec<_> = db
+            .query_files(CAMERA, None, None)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.id)
+            .collect();
+        assert_eq!(files, vec!["000000002.ts", "000000003.ts"]);
+        assert_eq!(db.upload_status(CAMERA, "000000001.ts").unwrap(), None);
+    }
+}
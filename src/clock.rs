@@ -0,0 +1,182 @@
+//! Wall-clock time of recorded frames.
+//!
+//! Chunks are indexed by when their first frame was captured. RTSP cameras that send RTCP sender
+//! reports tell us that directly: FFmpeg maps presentation timestamp 0 of the connection to the
+//! NTP time of the first report. Other sources only have their presentation timestamps, which are
+//! anchored to the system clock when the first frame of the connection arrives, and from then on
+//! advance with the stream rather than with the system clock, until the next reconnect. If the
+//! first sender report only arrives after recording started, the clock switches over to it at the
+//! next chunk, never moving back before the end of the chunks already recorded. How far the clock
+//! drifts from the system clock is recorded in [`Metrics`].
+//!
+//! [`Metrics`]: crate::metrics::Metrics
+
+use chrono::{DateTime, TimeDelta, Utc};
+use ffmpeg_next::Rational;
+
+/// RTCP times further than this from the system clock are ignored, the camera's clock is
+/// probably not synchronized.
+pub(crate) const MAX_RTCP_SKEW: TimeDelta = TimeDelta::seconds(60);
+
+/// Where the wall-clock times of a connection's frames come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// NTP timestamps from the RTCP sender reports of an RTSP source.
+    Rtcp,
+    /// The system clock when the first frame of the connection arrived.
+    System,
+}
+
+impl ClockSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClockSource::Rtcp => "rtcp",
+            ClockSource::System => "system",
+        }
+    }
+}
+
+/// Maps presentation timestamps of one connection's video stream to wall-clock time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamClock {
+    source: ClockSource,
+    anchor_time: DateTime<Utc>,
+    anchor_pts: i64,
+    time_base: Rational,
+}
+
+impl StreamClock {
+    /// A clock on which the frame at `pts` was presented at `now`.
+    pub fn system(pts: i64, time_base: Rational, now: DateTime<Utc>) -> Self {
+        Self {
+            source: ClockSource::System,
+            anchor_time: now,
+            anchor_pts: pts,
+            time_base,
+        }
+    }
+
+    /// A clock on which presentation timestamp 0 is `realtime_us` microseconds after the Unix
+    /// epoch, as FFmpeg reports for RTSP sources once the first RTCP sender report arrives.
+    /// Returns `None` if that puts the frame at `pts` more than [`MAX_RTCP_SKEW`] away from
+    /// `now`.
+    pub fn rtcp(
+        realtime_us: i64,
+        pts: i64,
+        time_base: Rational,
+        now: DateTime<Utc>,
+    ) -> Option<Self> {
+        let clock = Self {
+            source: ClockSource::Rtcp,
+            anchor_time: DateTime::from_timestamp_micros(realtime_us)?,
+            anchor_pts: 0,
+            time_base,
+        };
+
+        (clock.drift(pts, now).abs() <= MAX_RTCP_SKEW).then_some(clock)
+    }
+
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+
+    /// Wall-clock time of the frame at `pts`.
+    pub fn time_at(&self, pts: i64) -> DateTime<Utc> {
+        let elapsed_us =
+            (pts - self.anchor_pts) as i128 * 1_000_000 * self.time_base.numerator() as i128
+                / self.time_base.denominator() as i128;
+
+        self.anchor_time + TimeDelta::microseconds(elapsed_us as i64)
+    }
+
+    /// How far ahead of `now` the clock puts the frame at `pts`. Frames take a moment to arrive,
+    /// so this is usually slightly negative.
+    pub fn drift(&self, pts: i64, now: DateTime<Utc>) -> TimeDelta {
+        self.time_at(pts) - now
+    }
+
+    /// Switch from this clock to `next` at `pts`, where the frames timed by this clock end. If
+    /// `next` is behind, it's moved forward to meet this one there, so that the frames it times
+    /// can't start before those already timed by this clock ended.
+    pub fn hand_over(&self, next: StreamClock, pts: i64) -> StreamClock {
+        let behind = self.time_at(pts) - next.time_at(pts);
+        if behind <= TimeDelta::zero() {
+            return next;
+        }
+
+        StreamClock {
+            anchor_time: next.anchor_time + behind,
+            ..next
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use ffmpeg_next::Rational;
+
+    use crate::clock::{ClockSource, StreamClock};
+
+    #[test]
+    pub fn test_system_clock() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let clock = StreamClock::system(90_000, Rational(1, 90_000), t1);
+
+        assert_eq!(clock.source(), ClockSource::System);
+        assert_eq!(clock.time_at(90_000), t1);
+        assert_eq!(
+            clock.time_at(90_000 * 16 + 45_000),
+            t1 + TimeDelta::milliseconds(15_500)
+        );
+        // The system clock only counts at the start, a frame arriving late isn't moved.
+        assert_eq!(
+            clock.drift(90_000 * 16, t1 + TimeDelta::seconds(16)),
+            TimeDelta::seconds(-1)
+        );
+    }
+
+    #[test]
+    pub fn test_rtcp_clock() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let time_base = Rational(1, 90_000);
+
+        // Frames captured at t1 + 10s, arriving 200ms later.
+        let now = t1 + TimeDelta::milliseconds(10_200);
+        let clock = StreamClock::rtcp(t1.timestamp_micros(), 900_000, time_base, now).unwrap();
+        assert_eq!(clock.source(), ClockSource::Rtcp);
+        assert_eq!(clock.time_at(900_000), t1 + TimeDelta::seconds(10));
+        assert_eq!(clock.drift(900_000, now), TimeDelta::milliseconds(-200));
+
+        // A camera whose clock was never set.
+        let epoch = DateTime::<Utc>::from_timestamp(0, 0).unwrap();
+        assert!(StreamClock::rtcp(epoch.timestamp_micros(), 900_000, time_base, now).is_none());
+    }
+
+    #[test]
+    pub fn test_hand_over() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let time_base = Rational(1, 90_000);
+        let system = StreamClock::system(0, time_base, t1);
+        let end = 90_000 * 15;
+
+        // The camera's clock is 30s behind, switching to it as is would go back in time.
+        let now = t1 + TimeDelta::seconds(15);
+        let behind = (t1 - TimeDelta::seconds(30)).timestamp_micros();
+        let rtcp = StreamClock::rtcp(behind, end, time_base, now).unwrap();
+        let handed_over = system.hand_over(rtcp, end);
+        assert_eq!(handed_over.source(), ClockSource::Rtcp);
+        assert_eq!(handed_over.time_at(end), system.time_at(end));
+        assert_eq!(
+            handed_over.time_at(end + 90_000),
+            t1 + TimeDelta::seconds(16)
+        );
+
+        // Going forward is fine, that only leaves a gap.
+        let ahead = (t1 + TimeDelta::seconds(2)).timestamp_micros();
+        let rtcp = StreamClock::rtcp(ahead, end, time_base, now).unwrap();
+        assert_eq!(system.hand_over(rtcp, end), rtcp);
+    }
+}
@@ -0,0 +1,314 @@
+//! Configuration, from a TOML file with command line and environment overrides.
+//!
+//! Every setting can be given in the config file, in the environment or on the command line, in
+//! increasing order of precedence. Anything left unset falls back to the defaults below. The
+//! merged [`Settings`] are checked once at startup and turned into a [`Config`].
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::camera::{Camera, CameraRegistry};
+use crate::chunk::ChunkFormat;
+use crate::retention::{Retention, RetentionArgs};
+use crate::upload::storage::{Storage, StorageArgs};
+
+const DEFAULT_BIND: ([u8; 4], u16) = ([127, 0, 0, 1], 3030);
+const DEFAULT_DATABASE: &str = "v0.db";
+const DEFAULT_RECORDINGS: &str = "recordings";
+const DEFAULT_PREFIX: &str = "/";
+const DEFAULT_ROLL_SECONDS: u32 = 15;
+const DEFAULT_UPLOAD_DRAIN_TIMEOUT_SECS: u64 = 30;
+
+/// Settings as given in one place, with anything not given left unset.
+#[derive(Debug, Clone, Default, clap::Args, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Cameras to record, as `id=url`. A bare `url` records the default camera. Replaces the
+    /// cameras in the config file. In the environment, separate cameras with spaces.
+    #[clap(env = "CAMERARS_CAMERAS", value_delimiter = ' ')]
+    #[serde(default)]
+    pub cameras: Vec<Camera>,
+    /// Address to serve playlists and recordings on. Defaults to 127.0.0.1:3030.
+    #[clap(long, env = "CAMERARS_BIND")]
+    pub bind: Option<SocketAddr>,
+    /// SQLite database indexing the recordings. Defaults to `v0.db`.
+    #[clap(long, env = "CAMERARS_DATABASE")]
+    pub database: Option<PathBuf>,
+    /// Directory chunks are recorded into. Defaults to `recordings`.
+    #[clap(long, env = "CAMERARS_RECORDINGS")]
+    pub recordings: Option<PathBuf>,
+    /// Object-store prefix chunks are uploaded under. Defaults to `/`.
+    #[clap(long, env = "CAMERARS_PREFIX")]
+    pub prefix: Option<String>,
+    /// Container format to record chunks in. Defaults to MPEG-TS.
+    #[clap(long, value_enum, env = "CAMERARS_FORMAT")]
+    pub format: Option<ChunkFormat>,
+    /// Start a new chunk on the first keyframe after this many seconds. Defaults to 15.
+    #[clap(long, env = "CAMERARS_ROLL_SECONDS")]
+    pub roll_seconds: Option<u32>,
+    /// Evict uploaded chunks from local disk while a camera's recordings take up more than this
+    /// many bytes.
+    #[clap(long, env = "CAMERARS_LOCAL_QUOTA_BYTES")]
+    pub local_quota_bytes: Option<u64>,
+    /// On shutdown, wait up to this many seconds for queued chunks to finish uploading. Defaults
+    /// to 30.
+    #[clap(long, env = "CAMERARS_UPLOAD_DRAIN_TIMEOUT_SECS")]
+    pub upload_drain_timeout_secs: Option<u64>,
+    #[clap(flatten)]
+    #[serde(default)]
+    pub storage: StorageArgs,
+    #[clap(flatten)]
+    #[serde(default)]
+    pub retention: RetentionArgs,
+}
+
+impl Settings {
+    /// Read settings from the TOML file at `path`.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+
+        Self::parse(&contents).with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// These settings, with anything missing taken from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            cameras: if self.cameras.is_empty() {
+                fallback.cameras
+            } else {
+                self.cameras
+            },
+            bind: self.bind.or(fallback.bind),
+            database: self.database.or(fallback.database),
+            recordings: self.recordings.or(fallback.recordings),
+            prefix: self.prefix.or(fallback.prefix),
+            format: self.format.or(fallback.format),
+            roll_seconds: self.roll_seconds.or(fallback.roll_seconds),
+            local_quota_bytes: self.local_quota_bytes.or(fallback.local_quota_bytes),
+            upload_drain_timeout_secs: self
+                .upload_drain_timeout_secs
+                .or(fallback.upload_drain_timeout_secs),
+            storage: self.storage.or(fallback.storage),
+            retention: self.retention.or(fallback.retention),
+        }
+    }
+}
+
+/// The complete, checked configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub cameras: CameraRegistry,
+    pub bind: SocketAddr,
+    pub database: PathBuf,
+    pub recordings: PathBuf,
+    pub prefix: String,
+    pub format: ChunkFormat,
+    pub roll_seconds: u32,
+    pub local_quota_bytes: Option<u64>,
+    pub upload_drain_timeout: Duration,
+    pub storage: Storage,
+    pub retention: Retention,
+    pub prune_dry_run: bool,
+}
+
+impl Config {
+    /// Layer `overrides` over the config file at `path`, if there is one.
+    pub fn load(path: Option<&Path>, overrides: Settings) -> anyhow::Result<Self> {
+        let file = match path {
+            Some(path) => Settings::read(path)?,
+            None => Settings::default(),
+        };
+
+        Self::try_from(overrides.or(file))
+    }
+}
+
+impl TryFrom<Settings> for Config {
+    type Error = anyhow::Error;
+
+    fn try_from(settings: Settings) -> Result<Self, Self::Error> {
+        if settings.cameras.is_empty() {
+            bail!("no cameras configured, add a [[cameras]] table to the config file or pass them on the command line");
+        }
+        let cameras = CameraRegistry::new(settings.cameras)?;
+
+        let roll_seconds = settings.roll_seconds.unwrap_or(DEFAULT_ROLL_SECONDS);
+        if roll_seconds == 0 {
+            bail!("roll_seconds must be at least 1");
+        }
+        if settings.local_quota_bytes == Some(0) {
+            bail!("local_quota_bytes must be more than 0, leave it out to disable the quota");
+        }
+
+        let retention = Retention::from(&settings.retention);
+        let storage = Storage::try_from(settings.storage)?;
+
+        Ok(Self {
+            cameras,
+            bind: settings.bind.unwrap_or_else(|| DEFAULT_BIND.into()),
+            database: settings
+                .database
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DATABASE)),
+            recordings: settings
+                .recordings
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_RECORDINGS)),
+            prefix: settings
+                .prefix
+                .unwrap_or_else(|| DEFAULT_PREFIX.to_string()),
+            format: settings.format.unwrap_or_default(),
+            roll_seconds,
+            local_quota_bytes: settings.local_quota_bytes,
+            upload_drain_timeout: Duration::from_secs(
+                settings
+                    .upload_drain_timeout_secs
+                    .unwrap_or(DEFAULT_UPLOAD_DRAIN_TIMEOUT_SECS),
+            ),
+            storage,
+            retention,
+            prune_dry_run: settings.retention.prune_dry_run.unwrap_or_default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use chrono::TimeDelta;
+
+    use crate::camera::Camera;
+    use crate::chunk::ChunkFormat;
+    use crate::config::{Config, Settings};
+    use crate::retention::RetentionArgs;
+    use crate::upload::storage::Storage;
+
+    #[test]
+    pub fn test_example() {
+        let settings = Settings::parse(include_str!("../camerars.example.toml")).unwrap();
+        let config = Config::try_from(settings).unwrap();
+
+        assert_eq!(config.cameras.ids(), vec!["porch", "driveway"]);
+        assert_eq!(config.bind.to_string(), "127.0.0.1:3030");
+        assert_eq!(config.format, ChunkFormat::Fmp4);
+        assert_eq!(
+            config.storage,
+            Storage::S3 {
+                bucket: Some("recordings-s3-bucket".to_string())
+            }
+        );
+        assert_eq!(config.retention.local.max_age, Some(TimeDelta::days(7)));
+        assert_eq!(config.retention.remote.max_age, Some(TimeDelta::days(90)));
+    }
+
+    #[test]
+    pub fn test_defaults() {
+        let settings = Settings::parse(
+            r#"
+            [[cameras]]
+            url = "rtsp://192.168.1.10/live0"
+            "#,
+        )
+        .unwrap();
+        let config = Config::try_from(settings).unwrap();
+
+        assert!(config.cameras.get(Camera::DEFAULT_ID).is_some());
+        assert_eq!(config.bind.to_string(), "127.0.0.1:3030");
+        assert_eq!(config.database, PathBuf::from("v0.db"));
+        assert_eq!(config.recordings, PathBuf::from("recordings"));
+        assert_eq!(config.prefix, "/");
+        assert_eq!(config.format, ChunkFormat::Ts);
+        assert_eq!(config.roll_seconds, 15);
+        assert_eq!(config.storage, Storage::S3 { bucket: None });
+        assert!(!config.prune_dry_run);
+    }
+
+    #[test]
+    pub fn test_overrides() {
+        let file = Settings::parse(
+            r#"
+            bind = "0.0.0.0:8080"
+            roll_seconds = 30
+
+            [[cameras]]
+            id = "porch"
+            url = "rtsp://porch"
+
+            [storage]
+            kind = "local"
+            path = "/mnt/nas"
+
+            [retention]
+            prune_dry_run = true
+            "#,
+        )
+        .unwrap();
+        let overrides = Settings {
+            roll_seconds: Some(10),
+            retention: RetentionArgs {
+                prune_dry_run: Some(false),
+                ..RetentionArgs::default()
+            },
+            ..Settings::default()
+        };
+
+        let config = Config::try_from(overrides.or(file)).unwrap();
+        assert_eq!(config.bind.to_string(), "0.0.0.0:8080");
+        assert_eq!(config.roll_seconds, 10);
+        assert!(!config.prune_dry_run);
+        assert_eq!(config.cameras.ids(), vec!["porch"]);
+        assert_eq!(
+            config.storage,
+            Storage::Local {
+                path: PathBuf::from("/mnt/nas")
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_invalid() {
+        let error = |contents: &str| match Settings::parse(contents).and_then(Config::try_from) {
+            Ok(_) => panic!("config should be rejected:\n{contents}"),
+            Err(e) => format!("{e:#}"),
+        };
+
+        assert!(error("").contains("no cameras configured"));
+        assert!(error("rol_seconds = 10").contains("unknown field `rol_seconds`"));
+        assert!(error("bind = \"localhost\"").contains("invalid socket address"));
+        assert!(error(
+            r#"
+            [[cameras]]
+            id = "../etc"
+            url = "rtsp://porch"
+            "#
+        )
+        .contains("must be non-empty"));
+        assert!(error(
+            r#"
+            roll_seconds = 0
+
+            [[cameras]]
+            url = "rtsp://porch"
+            "#
+        )
+        .contains("roll_seconds"));
+        assert!(error(
+            r#"
+            [[cameras]]
+            url = "rtsp://porch"
+
+            [storage]
+            kind = "local"
+            "#
+        )
+        .contains("storage.path"));
+    }
+}
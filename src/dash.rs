@@ -0,0 +1,314 @@
+//! MPEG-DASH manifests, rendered from the same files as the HLS playlists.
+//!
+//! Files are listed with a `SegmentList` per `Period`. A new period starts whenever the media
+//! timeline does, after a reconnect, or the init segment changes, since that means the encoding
+//! did. Periods are placed by the recorded wall-clock start times, so gaps in the recording show
+//! up as gaps between them, while the `SegmentTimeline` within a period follows the presentation
+//! times in the segments themselves, as players expect.
+//!
+//! Only fragmented MP4 recordings can be served, the `isoff-live` profile doesn't allow MPEG-TS.
+//! Files indexed before their presentation times were recorded are left out.
+
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+use warp::reply::Response;
+use warp::{http, Reply};
+
+use crate::playlist::{PlaylistKind, TimedFile, MAX_DRIFT};
+
+/// Timescale of the segment timeline, in ticks per second.
+const TIMESCALE: i64 = 1000;
+
+/// [`MAX_DRIFT`] in ticks.
+const MAX_DRIFT_TICKS: i64 = MAX_DRIFT.num_milliseconds() * TIMESCALE / 1000;
+
+/// Nominal bandwidth advertised for the single representation, the MPD requires one.
+const BANDWIDTH: u64 = 2_000_000;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Manifest {
+    pub kind: PlaylistKind,
+    pub files: Vec<TimedFile>,
+}
+
+impl Manifest {
+    /// Whether any of the files is MPEG-TS, which can't be served over DASH.
+    pub fn has_mpeg_ts(&self) -> bool {
+        self.files.iter().any(|timed| timed.file.init.is_none())
+    }
+
+    pub fn render(&self, now: DateTime<Utc>) -> String {
+        let files: Vec<&TimedFile> = self
+            .files
+            .iter()
+            .filter(|timed| timed.file.init.is_some() && timed.file.media_start.is_some())
+            .collect();
+
+        // Live manifests are anchored to the epoch so that times stay put as the window slides,
+        // on-demand ones to their first file.
+        let anchor = match self.kind {
+            PlaylistKind::VOD => files
+                .first()
+                .map(|timed| timed.start_time)
+                .unwrap_or(DateTime::UNIX_EPOCH),
+            PlaylistKind::LIVE => DateTime::UNIX_EPOCH,
+        };
+        let ticks = |time: DateTime<Utc>| (time - anchor).num_milliseconds();
+
+        let mut mpd = String::new();
+        mpd.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        mpd.push_str("<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\"");
+        mpd.push_str(" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\"");
+        mpd.push_str(" minBufferTime=\"PT2S\"");
+        match self.kind {
+            PlaylistKind::VOD => {
+                let end = files
+                    .last()
+                    .map(|timed| ticks(timed.start_time) + duration_ticks(timed.file.duration))
+                    .unwrap_or_default();
+                write!(
+                    mpd,
+                    " type=\"static\" mediaPresentationDuration=\"{}\"",
+                    duration(end)
+                )
+                .unwrap();
+            }
+            PlaylistKind::LIVE => {
+                let longest = files
+                    .iter()
+                    .map(|timed| duration_ticks(timed.file.duration))
+                    .max()
+                    .unwrap_or(TIMESCALE);
+                let window = files
+                    .iter()
+                    .map(|timed| duration_ticks(timed.file.duration))
+                    .sum();
+                write!(
+                    mpd,
+                    " type=\"dynamic\" availabilityStartTime=\"{}\" publishTime=\"{}\" minimumUpdatePeriod=\"{}\" timeShiftBufferDepth=\"{}\"",
+                    date_time(anchor),
+                    date_time(now),
+                    duration(longest),
+                    duration(window),
+                )
+                .unwrap();
+            }
+        }
+        mpd.push_str(">\n");
+
+        let same_period = |a: &&TimedFile, b: &&TimedFile| {
+            a.file.init == b.file.init
+                && (media_ticks(b) - (media_ticks(a) + duration_ticks(a.file.duration))).abs()
+                    <= MAX_DRIFT_TICKS
+        };
+        for period in files.chunk_by(same_period) {
+            let start = ticks(period[0].start_time);
+            let offset = media_ticks(period[0]);
+            writeln!(
+                mpd,
+                "  <Period id=\"{}\" start=\"{}\">",
+                start,
+                duration(start)
+            )
+            .unwrap();
+            mpd.push_str("    <AdaptationSet contentType=\"video\" segmentAlignment=\"true\">\n");
+            writeln!(
+                mpd,
+                "      <Representation id=\"0\" mimeType=\"video/mp4\" bandwidth=\"{BANDWIDTH}\">"
+            )
+            .unwrap();
+            writeln!(
+                mpd,
+                "        <SegmentList timescale=\"{TIMESCALE}\" presentationTimeOffset=\"{offset}\">"
+            )
+            .unwrap();
+            if let Some(init) = &period[0].file.init {
+                writeln!(
+                    mpd,
+                    "          <Initialization sourceURL=\"files/{}\"/>",
+                    escape(init)
+                )
+                .unwrap();
+            }
+
+            mpd.push_str("          <SegmentTimeline>\n");
+            for timed in period {
+                let t = media_ticks(timed);
+                let d = duration_ticks(timed.file.duration);
+                writeln!(mpd, "            <S t=\"{t}\" d=\"{d}\"/>").unwrap();
+            }
+            mpd.push_str("          </SegmentTimeline>\n");
+
+            for timed in period {
+                writeln!(
+                    mpd,
+                    "          <SegmentURL media=\"files/{}\"/>",
+                    escape(&timed.file.id)
+                )
+                .unwrap();
+            }
+
+            mpd.push_str("        </SegmentList>\n");
+            mpd.push_str("      </Representation>\n");
+            mpd.push_str("    </AdaptationSet>\n");
+            mpd.push_str("  </Period>\n");
+        }
+
+        mpd.push_str("</MPD>\n");
+        mpd
+    }
+}
+
+impl Reply for Manifest {
+    fn into_response(self) -> Response {
+        http::Response::builder()
+            .header("content-type", "application/dash+xml")
+            .body(self.render(Utc::now()).into())
+            .unwrap()
+    }
+}
+
+fn duration_ticks(seconds: f64) -> i64 {
+    (seconds * TIMESCALE as f64).round() as i64
+}
+
+/// Presentation time of the first frame of a file, in ticks on the stream's timeline.
+fn media_ticks(timed: &TimedFile) -> i64 {
+    duration_ticks(timed.file.media_start.unwrap_or_default())
+}
+
+/// An `xs:duration` of `ticks`.
+fn duration(ticks: i64) -> String {
+    format!("PT{}.{:03}S", ticks / TIMESCALE, ticks % TIMESCALE)
+}
+
+fn date_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use crate::dash::Manifest;
+    use crate::playlist::{PlaylistFile, PlaylistKind, TimedFile};
+
+    fn timed(start_time: DateTime<Utc>, media_start: f64, id: &str, init: &str) -> TimedFile {
+        TimedFile {
+            start_time,
+            file: PlaylistFile {
+                duration: 15.0,
+                id: id.to_string(),
+                init: Some(init.to_string()),
+                media_start: Some(media_start),
+            },
+        }
+    }
+
+    #[test]
+    pub fn test_on_demand() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let mut legacy = timed(
+            t1 + TimeDelta::seconds(90),
+            0.0,
+            "000000005.m4s",
+            "init-b.mp4",
+        );
+        legacy.file.media_start = None;
+        let manifest = Manifest {
+            kind: PlaylistKind::VOD,
+            files: vec![
+                timed(t1, 100.0, "000000001.m4s", "init-a.mp4"),
+                // Started a little late on the wall clock, but carries on from the first one.
+                timed(
+                    t1 + TimeDelta::milliseconds(15_200),
+                    115.0,
+                    "000000002.m4s",
+                    "init-a.mp4",
+                ),
+                // After a reconnect, which restarted the timestamps.
+                timed(
+                    t1 + TimeDelta::seconds(60),
+                    0.5,
+                    "000000003.m4s",
+                    "init-a.mp4",
+                ),
+                // With new stream parameters.
+                timed(
+                    t1 + TimeDelta::seconds(75),
+                    15.5,
+                    "000000004.m4s",
+                    "init-b.mp4",
+                ),
+                legacy,
+            ],
+        };
+        assert!(!manifest.has_mpeg_ts());
+
+        let mpd = manifest.render(t1);
+        assert!(mpd.contains("type=\"static\" mediaPresentationDuration=\"PT90.000S\""));
+        assert_eq!(mpd.matches("<Period ").count(), 3);
+        assert!(mpd.contains("<Initialization sourceURL=\"files/init-a.mp4\"/>"));
+        assert!(mpd.contains("<Initialization sourceURL=\"files/init-b.mp4\"/>"));
+        assert!(mpd.contains("presentationTimeOffset=\"100000\""));
+        assert!(mpd
+            .contains("<S t=\"100000\" d=\"15000\"/>\n            <S t=\"115000\" d=\"15000\"/>"));
+        assert!(mpd.contains("<Period id=\"60000\" start=\"PT60.000S\">"));
+        assert!(mpd.contains("presentationTimeOffset=\"500\""));
+        assert!(mpd.contains("<S t=\"500\" d=\"15000\"/>"));
+        assert!(mpd.contains("<Period id=\"75000\" start=\"PT75.000S\">"));
+        assert!(mpd.contains("<SegmentURL media=\"files/000000004.m4s\"/>"));
+        assert!(!mpd.contains("000000005.m4s"));
+    }
+
+    #[test]
+    pub fn test_live() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let manifest = Manifest {
+            kind: PlaylistKind::LIVE,
+            files: vec![
+                timed(t1, 3600.0, "000000001.m4s", "init-a.mp4"),
+                timed(
+                    t1 + TimeDelta::seconds(15),
+                    3615.0,
+                    "000000002.m4s",
+                    "init-a.mp4",
+                ),
+            ],
+        };
+
+        let mpd = manifest.render(t1 + TimeDelta::seconds(30));
+        assert!(mpd.contains("type=\"dynamic\""));
+        assert!(mpd.contains("availabilityStartTime=\"1970-01-01T00:00:00.000Z\""));
+        assert!(mpd.contains("publishTime=\"2000-01-01T00:00:30.000Z\""));
+        assert!(mpd.contains("timeShiftBufferDepth=\"PT30.000S\""));
+        assert!(mpd.contains("<Period id=\"946684800000\""));
+        assert!(mpd.contains("presentationTimeOffset=\"3600000\""));
+        assert!(mpd.contains("<S t=\"3600000\" d=\"15000\"/>"));
+        assert!(mpd.contains("<S t=\"3615000\" d=\"15000\"/>"));
+    }
+
+    #[test]
+    pub fn test_mpeg_ts() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let mut file = timed(t1, 0.0, "000000001.ts", "");
+        file.file.init = None;
+        let manifest = Manifest {
+            kind: PlaylistKind::VOD,
+            files: vec![file],
+        };
+
+        assert!(manifest.has_mpeg_ts());
+    }
+}
@@ -1,10 +1,14 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
+use rusqlite::OptionalExtension;
+use tracing::{info, warn};
 
-use crate::playlist::PlaylistFile;
+use crate::chunk::seq_num;
+use crate::playlist::{is_discontinuity, Gap, PlaylistFile, TimedFile};
+use crate::upload::queue::{UploadStatus, UploadTask};
 
 /// Database for keeping track of a set of video files, used to construct new queries.
 #[derive(Clone)]
@@ -15,42 +19,157 @@ pub struct Database {
 impl Database {
     pub fn memory() -> Self {
         // Construct a new SQLite database in-memory.
-        let db = rusqlite::Connection::open_in_memory().unwrap();
-        setup_connection(&db);
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        setup_connection(&mut db).unwrap();
 
         let db = Arc::new(Mutex::new(db));
         Self { inner: db }
     }
 
-    pub fn file<P: AsRef<Path>>(file: P) -> Self {
-        let db = rusqlite::Connection::open(file).unwrap();
-        setup_connection(&db);
+    /// Open the database at `file`, creating or migrating it as needed.
+    pub fn file<P: AsRef<Path>>(file: P) -> Result<Self, DbError> {
+        let mut db = rusqlite::Connection::open(file)?;
+        setup_connection(&mut db)?;
 
         let db = Arc::new(Mutex::new(db));
-        Self { inner: db }
+        Ok(Self { inner: db })
     }
 }
 
 impl Database {
-    pub fn append_file(&self, ts: DateTime<Utc>, file: PlaylistFile) {
+    pub fn append_file(
+        &self,
+        camera_id: &str,
+        ts: DateTime<Utc>,
+        file: PlaylistFile,
+    ) -> Result<(), DbError> {
         // insert a new playlist file.
         // Query for playlist files, if possible.
         let db = self.inner.lock().unwrap();
 
         // We should be holding on to a writer as soon as we append a new file here.
 
+        insert_file(
+            &db,
+            camera_id,
+            &TimedFile {
+                start_time: ts,
+                file,
+            },
+        )?;
+
+        Ok(())
+    }
+
+    /// Replace everything indexed for a camera with `files` and `gaps`, in one transaction.
+    pub fn replace_index(
+        &self,
+        camera_id: &str,
+        files: &[TimedFile],
+        gaps: &[Gap],
+    ) -> Result<(), DbError> {
+        let mut db = self.inner.lock().unwrap();
+
+        let tx = db.transaction()?;
+        tx.execute("DELETE FROM video_files WHERE camera_id = ?1", [camera_id])?;
+        tx.execute("DELETE FROM gaps WHERE camera_id = ?1", [camera_id])?;
+        // Gaps go in first, they count towards the discontinuities of the files after them.
+        for gap in gaps {
+            tx.execute(
+                "INSERT INTO gaps (camera_id, start_time, end_time) VALUES (?1, ?2, ?3)",
+                (camera_id, gap.start_time, gap.end_time),
+            )?;
+        }
+        for timed in files {
+            insert_file(&tx, camera_id, timed)?;
+        }
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    /// Record a period during which the input was disconnected and nothing was recorded.
+    pub fn append_gap(
+        &self,
+        camera_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let db = self.inner.lock().unwrap();
+
         db.execute(
-            "INSERT INTO video_files VALUES (?1, ?2, ?3)",
-            (file.id.as_str(), ts, file.duration),
-        )
-        .unwrap();
+            "INSERT INTO gaps (camera_id, start_time, end_time) VALUES (?1, ?2, ?3)",
+            (camera_id, start, end),
+        )?;
+
+        Ok(())
+    }
+
+    /// Gaps that started between `start` and `end`.
+    pub fn query_gaps(
+        &self,
+        camera_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Gap>, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        Ok(gaps_starting_between(&db, camera_id, start, end)?)
     }
 
+    /// Gaps that were ongoing at any point between `start` and `end`.
+    pub fn query_overlapping_gaps(
+        &self,
+        camera_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Gap>, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let mut stmt = db.prepare(
+            r#"
+            SELECT start_time, end_time FROM gaps
+            WHERE camera_id = ?1 AND start_time <= ?3 AND end_time >= ?2
+            ORDER BY start_time
+            "#,
+        )?;
+
+        let rows = stmt
+            .query_map((camera_id, start, end), gap)?
+            .collect::<Result<_, _>>()?;
+
+        Ok(rows)
+    }
+
+    /// Files that were recording at any point between `start` and `end`, oldest first. A missing
+    /// bound leaves that end of the range open.
     pub fn query_files(
         &self,
+        camera_id: &str,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Result<Vec<PlaylistFile>, DbError> {
+        let files = self
+            .query_timed_files(camera_id, start, end)?
+            .into_iter()
+            .map(|timed| timed.file)
+            .collect();
+
+        Ok(files)
+    }
+
+    /// Like [`Database::query_files`], but along with the start time of each file.
+    ///
+    /// A file overlaps the range when `start_time < end AND end_time > start`. Both ends of the
+    /// scan over the `(camera_id, start_time)` index are kept tight by starting from the last file
+    /// to start before `start`, which relies on a camera's files following one another rather
+    /// than overlapping.
+    pub fn query_timed_files(
+        &self,
+        camera_id: &str,
         start: Option<DateTime<Utc>>,
         end: Option<DateTime<Utc>>,
-    ) -> Vec<PlaylistFile> {
+    ) -> Result<Vec<TimedFile>, DbError> {
         let db = self.inner.lock().unwrap();
 
         let start =
@@ -58,46 +177,833 @@ impl Database {
         let end = end.unwrap_or_else(|| DateTime::<Utc>::from_str("9999-12-31 23:59:59Z").unwrap());
 
         let mut stmt = db.prepare(
-            "SELECT file_id, duration FROM video_files WHERE datetime(start_time) BETWEEN datetime(?1) AND datetime(?2)")
-            .unwrap();
+            r#"
+            SELECT file_id, duration, init_id, start_time, media_start FROM video_files
+            WHERE camera_id = ?1
+                AND start_time >= COALESCE(
+                    (SELECT MAX(start_time) FROM video_files WHERE camera_id = ?1 AND start_time <= ?2),
+                    ?2
+                )
+                AND start_time < ?3
+                AND end_time > ?2
+            ORDER BY start_time, file_id
+            "#,
+        )?;
 
         let rows = stmt
-            .query_map((start, end), |row| {
-                Ok(PlaylistFile {
-                    id: row.get(0)?,
-                    duration: row.get(1)?,
-                })
-            })
-            .unwrap()
-            .map(|item| item.unwrap())
+            .query_map((camera_id, start, end), timed_file)?
+            .collect::<Result<_, _>>()?;
+
+        Ok(rows)
+    }
+
+    /// The `limit` most recent files for a camera, oldest first.
+    pub fn query_latest_files(
+        &self,
+        camera_id: &str,
+        limit: usize,
+    ) -> Result<Vec<PlaylistFile>, DbError> {
+        let files = self
+            .query_latest_timed_files(camera_id, limit)?
+            .into_iter()
+            .map(|timed| timed.file)
             .collect();
 
-        rows
+        Ok(files)
+    }
+
+    /// Like [`Database::query_latest_files`], but along with the start time of each file.
+    pub fn query_latest_timed_files(
+        &self,
+        camera_id: &str,
+        limit: usize,
+    ) -> Result<Vec<TimedFile>, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let mut stmt = db.prepare(
+            r#"
+            SELECT file_id, duration, init_id, start_time, media_start FROM (
+                SELECT file_id, duration, init_id, start_time, media_start FROM video_files
+                WHERE camera_id = ?1
+                ORDER BY start_time DESC, file_id DESC
+                LIMIT ?2
+            ) ORDER BY start_time ASC, file_id ASC
+            "#,
+        )?;
+
+        let files = stmt
+            .query_map((camera_id, limit), timed_file)?
+            .collect::<Result<_, _>>()?;
+
+        Ok(files)
+    }
+
+    /// Number of discontinuities before `timed`, counting the one right before it, see
+    /// [`is_discontinuity`]. Zero for files that aren't indexed.
+    pub fn discontinuity_sequence(
+        &self,
+        camera_id: &str,
+        timed: &TimedFile,
+    ) -> Result<u64, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let sequence = db
+            .query_row(
+                r#"
+                SELECT discontinuity_sequence FROM video_files
+                WHERE camera_id = ?1 AND start_time = ?2 AND file_id = ?3
+                "#,
+                (camera_id, timed.start_time, timed.file.id.as_str()),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(sequence.unwrap_or_default())
+    }
+
+    /// Media sequence number of `timed`, one more than that of the camera's file before it. One
+    /// for files that aren't indexed.
+    pub fn media_sequence(&self, camera_id: &str, timed: &TimedFile) -> Result<u64, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let sequence = db
+            .query_row(
+                r#"
+                SELECT media_sequence FROM video_files
+                WHERE camera_id = ?1 AND start_time = ?2 AND file_id = ?3
+                "#,
+                (camera_id, timed.start_time, timed.file.id.as_str()),
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(sequence.unwrap_or(1))
+    }
+
+    /// Forget about files that were deleted from storage, along with their uploads.
+    pub fn remove_files(&self, camera_id: &str, file_ids: &[String]) -> Result<(), DbError> {
+        let mut db = self.inner.lock().unwrap();
+
+        let tx = db.transaction()?;
+        for file_id in file_ids {
+            tx.execute(
+                "DELETE FROM video_files WHERE camera_id = ?1 AND file_id = ?2",
+                (camera_id, file_id),
+            )?;
+            tx.execute(
+                "DELETE FROM uploads WHERE camera_id = ?1 AND file_id = ?2",
+                (camera_id, file_id),
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(())
     }
 }
 
-fn setup_connection(db: &rusqlite::Connection) {
-    db.execute_batch(
+/// Persistent upload queue, see [`crate::upload::queue`].
+impl Database {
+    /// Queue a chunk for upload, unless it was already uploaded.
+    pub fn enqueue_upload(
+        &self,
+        camera_id: &str,
+        file_id: &str,
+        path: &Path,
+        now: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let db = self.inner.lock().unwrap();
+
+        db.execute(
+            r#"
+            INSERT INTO uploads (camera_id, file_id, path, status, attempts, next_attempt)
+            VALUES (?1, ?2, ?3, ?4, 0, ?5)
+            ON CONFLICT (camera_id, file_id) DO UPDATE SET
+                path = excluded.path,
+                status = excluded.status,
+                attempts = 0,
+                next_attempt = excluded.next_attempt
+            WHERE status NOT IN (?6, ?7)
+            "#,
+            (
+                camera_id,
+                file_id,
+                path.to_string_lossy(),
+                UploadStatus::Pending.as_str(),
+                now,
+                UploadStatus::Done.as_str(),
+                UploadStatus::Pruned.as_str(),
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    /// Claim the pending upload that has been due the longest, marking it in progress.
+    pub fn claim_upload(&self, now: DateTime<Utc>) -> Result<Option<UploadTask>, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let task = db
+            .query_row(
+                r#"
+                SELECT camera_id, file_id, path, attempts FROM uploads
+                WHERE status = ?1 AND next_attempt <= ?2
+                ORDER BY next_attempt
+                LIMIT 1
+                "#,
+                (UploadStatus::Pending.as_str(), now),
+                |row| {
+                    Ok(UploadTask {
+                        camera_id: row.get(0)?,
+                        file_id: row.get(1)?,
+                        path: PathBuf::from(row.get::<_, String>(2)?),
+                        attempts: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        if let Some(task) = &task {
+            set_upload_status(
+                &db,
+                &task.camera_id,
+                &task.file_id,
+                UploadStatus::InProgress,
+            )?;
+        }
+
+        Ok(task)
+    }
+
+    /// When the next pending upload becomes due, if there are any.
+    pub fn next_upload_due(&self) -> Result<Option<DateTime<Utc>>, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let due = db.query_row(
+            "SELECT MIN(next_attempt) FROM uploads WHERE status = ?1",
+            [UploadStatus::Pending.as_str()],
+            |row| row.get(0),
+        )?;
+
+        Ok(due)
+    }
+
+    /// Number of uploads that are still pending or in progress, across all cameras.
+    pub fn count_unfinished_uploads(&self) -> Result<u64, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let count = db.query_row(
+            "SELECT COUNT(*) FROM uploads WHERE status IN (?1, ?2)",
+            [
+                UploadStatus::Pending.as_str(),
+                UploadStatus::InProgress.as_str(),
+            ],
+            |row| row.get(0),
+        )?;
+
+        Ok(count)
+    }
+
+    pub fn complete_upload(&self, camera_id: &str, file_id: &str) -> Result<(), DbError> {
+        let db = self.inner.lock().unwrap();
+
+        set_upload_status(&db, camera_id, file_id, UploadStatus::Done)?;
+
+        Ok(())
+    }
+
+    /// Record that an uploaded chunk was deleted from remote storage, leaving only the local
+    /// copy.
+    pub fn prune_upload(&self, camera_id: &str, file_id: &str) -> Result<(), DbError> {
+        let db = self.inner.lock().unwrap();
+
+        set_upload_status(&db, camera_id, file_id, UploadStatus::Pruned)?;
+
+        Ok(())
+    }
+
+    /// Put a failed upload back in the queue, to be retried at `next_attempt`.
+    pub fn retry_upload(
+        &self,
+        camera_id: &str,
+        file_id: &str,
+        error: &str,
+        next_attempt: DateTime<Utc>,
+    ) -> Result<(), DbError> {
+        let db = self.inner.lock().unwrap();
+
+        db.execute(
+            r#"
+            UPDATE uploads SET status = ?1, attempts = attempts + 1, next_attempt = ?2, last_error = ?3
+            WHERE camera_id = ?4 AND file_id = ?5
+            "#,
+            (
+                UploadStatus::Pending.as_str(),
+                next_attempt,
+                error,
+                camera_id,
+                file_id,
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    /// Give up on an upload that can never succeed.
+    pub fn fail_upload(&self, camera_id: &str, file_id: &str, error: &str) -> Result<(), DbError> {
+        let db = self.inner.lock().unwrap();
+
+        db.execute(
+            r#"
+            UPDATE uploads SET status = ?1, attempts = attempts + 1, last_error = ?2
+            WHERE camera_id = ?3 AND file_id = ?4
+            "#,
+            (UploadStatus::Failed.as_str(), error, camera_id, file_id),
+        )?;
+
+        Ok(())
+    }
+
+    /// Return uploads that were in progress when the process last stopped to the queue.
+    pub fn reset_interrupted_uploads(&self, camera_id: &str) -> Result<(), DbError> {
+        let db = self.inner.lock().unwrap();
+
+        db.execute(
+            "UPDATE uploads SET status = ?1 WHERE status = ?2 AND camera_id = ?3",
+            (
+                UploadStatus::Pending.as_str(),
+                UploadStatus::InProgress.as_str(),
+                camera_id,
+            ),
+        )?;
+
+        Ok(())
+    }
+
+    pub fn upload_status(
+        &self,
+        camera_id: &str,
+        file_id: &str,
+    ) -> Result<Option<UploadStatus>, DbError> {
+        let db = self.inner.lock().unwrap();
+
+        let status = db
+            .query_row(
+                "SELECT status FROM uploads WHERE camera_id = ?1 AND file_id = ?2",
+                (camera_id, file_id),
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+
+        Ok(status.and_then(|status| UploadStatus::from_str(&status).ok()))
+    }
+}
+
+/// Index a file after the camera's latest one, numbering it and its discontinuities on from there.
+///
+/// A camera's first file is numbered after its chunk sequence number where it has one, so that
+/// numbers carry on where they left off if everything before it was pruned.
+fn insert_file(
+    db: &rusqlite::Connection,
+    camera_id: &str,
+    timed: &TimedFile,
+) -> rusqlite::Result<()> {
+    let previous = db
+        .query_row(
+            r#"
+            SELECT file_id, duration, init_id, start_time, media_start, discontinuity_sequence,
+                media_sequence
+            FROM video_files
+            WHERE camera_id = ?1
+            ORDER BY start_time DESC, file_id DESC
+            LIMIT 1
+            "#,
+            [camera_id],
+            |row| {
+                Ok((
+                    timed_file(row)?,
+                    row.get::<_, u64>(5)?,
+                    row.get::<_, u64>(6)?,
+                ))
+            },
+        )
+        .optional()?;
+    let (discontinuity_sequence, media_sequence) = match previous {
+        Some((previous, discontinuity_sequence, media_sequence)) => {
+            let gaps = gaps_starting_between(db, camera_id, previous.start_time, timed.start_time)?;
+            let discontinuity = is_discontinuity(&previous, timed, &gaps);
+            (
+                discontinuity_sequence + discontinuity as u64,
+                media_sequence + 1,
+            )
+        }
+        None => (0, seq_num(&timed.file.id).unwrap_or(1)),
+    };
+
+    db.execute(
         r#"
+        INSERT INTO video_files (
+            camera_id, file_id, start_time, end_time, duration, init_id, media_start,
+            discontinuity_sequence, media_sequence
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+        "#,
+        (
+            camera_id,
+            timed.file.id.as_str(),
+            timed.start_time,
+            timed.end_time(),
+            timed.file.duration,
+            timed.file.init.as_deref(),
+            timed.file.media_start,
+            discontinuity_sequence,
+            media_sequence,
+        ),
+    )?;
+
+    Ok(())
+}
+
+/// Gaps of the camera that started between `start` and `end`, oldest first.
+fn gaps_starting_between(
+    db: &rusqlite::Connection,
+    camera_id: &str,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> rusqlite::Result<Vec<Gap>> {
+    let mut stmt = db.prepare_cached(
+        r#"
+        SELECT start_time, end_time FROM gaps
+        WHERE camera_id = ?1 AND start_time >= ?2 AND start_time <= ?3
+        ORDER BY start_time
+        "#,
+    )?;
+
+    let rows = stmt
+        .query_map((camera_id, start, end), gap)?
+        .collect::<Result<_, _>>()?;
+
+    Ok(rows)
+}
+
+/// Read a [`Gap`] from a row of `start_time, end_time`.
+fn gap(row: &rusqlite::Row) -> rusqlite::Result<Gap> {
+    Ok(Gap {
+        start_time: row.get(0)?,
+        end_time: row.get(1)?,
+    })
+}
+
+/// Read a [`TimedFile`] from a row of `file_id, duration, init_id, start_time, media_start`.
+fn timed_file(row: &rusqlite::Row) -> rusqlite::Result<TimedFile> {
+    Ok(TimedFile {
+        start_time: row.get(3)?,
+        file: PlaylistFile {
+            id: row.get(0)?,
+            duration: row.get(1)?,
+            init: row.get(2)?,
+            media_start: row.get(4)?,
+        },
+    })
+}
+
+fn set_upload_status(
+    db: &rusqlite::Connection,
+    camera_id: &str,
+    file_id: &str,
+    status: UploadStatus,
+) -> rusqlite::Result<()> {
+    db.execute(
+        "UPDATE uploads SET status = ?1 WHERE camera_id = ?2 AND file_id = ?3",
+        (status.as_str(), camera_id, file_id),
+    )?;
+
+    Ok(())
+}
+
+/// Schema migrations, in order. The migration at index `i` takes a database from `user_version`
+/// `i` to `i + 1`, so new ones must only ever be appended.
+///
+/// Databases from before versioning all have `user_version` 0, whatever columns they were given
+/// since, which is why the early migrations check before creating anything.
+const MIGRATIONS: &[Migration] = &[
+    // The original single-camera index.
+    |db| {
+        db.execute_batch(
+            r#"
             CREATE TABLE IF NOT EXISTS video_files (
                 file_id TEXT,
                 start_time DATETIME,
                 duration REAL
-            )
+            );
+            "#,
+        )
+    },
+    // Databases from before multi-camera support only ever recorded the default camera.
+    |db| {
+        add_column_if_missing(
+            db,
+            "video_files",
+            "camera_id",
+            "TEXT NOT NULL DEFAULT 'default'",
+        )
+    },
+    |db| {
+        db.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS gaps (
+                camera_id TEXT NOT NULL,
+                start_time DATETIME,
+                end_time DATETIME
+            );
+            "#,
+        )
+    },
+    |db| {
+        db.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS uploads (
+                camera_id TEXT NOT NULL,
+                file_id TEXT NOT NULL,
+                path TEXT NOT NULL,
+                status TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt DATETIME,
+                last_error TEXT,
+                PRIMARY KEY (camera_id, file_id)
+            );
             "#,
+        )
+    },
+    |db| add_column_if_missing(db, "video_files", "init_id", "TEXT"),
+    index_time_ranges,
+    |db| db.execute_batch("ALTER TABLE video_files ADD COLUMN media_start REAL"),
+    number_discontinuities,
+    number_media_sequences,
+    quarantine_malformed_rows,
+];
+
+type Migration = fn(&rusqlite::Connection) -> rusqlite::Result<()>;
+
+/// Schema version written by this build.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Errors from the [`Database`]. Besides SQLite failing outright, e.g. because the file is
+/// locked, corrupt or on a full disk, the schema may be one this build can't work with.
+#[derive(Debug, thiserror::Error)]
+pub enum DbError {
+    #[error(
+        "database has schema version {found}, newer than the {SCHEMA_VERSION} this build supports"
+    )]
+    TooNew { found: u32 },
+
+    #[error("failed to migrate database to schema version {version}: {source}")]
+    Migration {
+        version: u32,
+        source: rusqlite::Error,
+    },
+
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Bring the schema up to [`SCHEMA_VERSION`], one migration and transaction at a time.
+fn setup_connection(db: &mut rusqlite::Connection) -> Result<(), DbError> {
+    let version: u32 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(DbError::TooNew { found: version });
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = from as u32 + 1;
+        let migrate = |tx: &rusqlite::Transaction| {
+            migration(tx)?;
+            tx.pragma_update(None, "user_version", version)
+        };
+
+        let tx = db.transaction()?;
+        migrate(&tx).map_err(|source| DbError::Migration { version, source })?;
+        tx.commit()?;
+        info!("migrated database to schema version {version}");
+    }
+
+    Ok(())
+}
+
+/// Store when each file ends, and index files and gaps by camera and start time.
+///
+/// Times are compared as text so that the indexes can be used, which only orders them correctly
+/// when they're all written the same way, so older rows are rewritten in the format used for
+/// query parameters.
+///
+/// Files with a duration that can't be read end where they start. Rows with a start time that
+/// can't be read are left as they are, there's nothing to index them by.
+fn index_time_ranges(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch("ALTER TABLE video_files ADD COLUMN end_time DATETIME")?;
+
+    let files: Vec<(
+        rusqlite::Result<TimedFile>,
+        rusqlite::Result<DateTime<Utc>>,
+        i64,
+    )> = db
+        .prepare("SELECT file_id, duration, init_id, start_time, NULL, rowid FROM video_files")?
+        .query_map([], |row| Ok((timed_file(row), row.get(3), row.get(5)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut update =
+        db.prepare("UPDATE video_files SET start_time = ?1, end_time = ?2 WHERE rowid = ?3")?;
+    for (timed, start_time, rowid) in files {
+        let end_time = timed.as_ref().ok().and_then(checked_end_time);
+        let (start_time, end_time) = match (start_time, end_time) {
+            (Ok(start_time), Some(end_time)) => (start_time, end_time),
+            (Ok(start_time), None) => {
+                warn!(
+                    rowid,
+                    "file has a malformed duration, ending it where it starts"
+                );
+                (start_time, start_time)
+            }
+            (Err(e), _) => {
+                warn!(error = %e, rowid, "skipping file with a malformed start time");
+                continue;
+            }
+        };
+        update.execute((start_time, end_time, rowid))?;
+    }
+
+    let gaps: Vec<(rusqlite::Result<Gap>, i64)> = db
+        .prepare("SELECT start_time, end_time, rowid FROM gaps")?
+        .query_map([], |row| Ok((gap(row), row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut update =
+        db.prepare("UPDATE gaps SET start_time = ?1, end_time = ?2 WHERE rowid = ?3")?;
+    for (gap, rowid) in gaps {
+        match gap {
+            Ok(gap) => update.execute((gap.start_time, gap.end_time, rowid))?,
+            Err(e) => {
+                warn!(error = %e, rowid, "skipping malformed gap");
+                continue;
+            }
+        };
+    }
+
+    db.execute_batch(
+        r#"
+        CREATE INDEX video_files_by_time ON video_files (camera_id, start_time);
+        CREATE INDEX gaps_by_time ON gaps (camera_id, start_time);
+        "#,
     )
-    .unwrap();
+}
+
+/// Store the number of discontinuities before each file, so that live playlists can tell how
+/// many slid out of their window. Existing files are numbered in order, skipping over any that
+/// can't be read, which take the number of the file before them.
+fn number_discontinuities(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE video_files ADD COLUMN discontinuity_sequence INTEGER NOT NULL DEFAULT 0",
+    )?;
+
+    let cameras: Vec<String> = db
+        .prepare("SELECT DISTINCT camera_id FROM video_files")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let mut update =
+        db.prepare("UPDATE video_files SET discontinuity_sequence = ?1 WHERE rowid = ?2")?;
+    for camera_id in cameras {
+        // Malformed gaps were already warned about by `index_time_ranges`.
+        let gaps: Vec<Gap> = db
+            .prepare(
+                "SELECT start_time, end_time FROM gaps WHERE camera_id = ?1 ORDER BY start_time",
+            )?
+            .query_map([&camera_id], gap)?
+            .filter_map(Result::ok)
+            .collect();
+        let files: Vec<(rusqlite::Result<TimedFile>, i64)> = db
+            .prepare(
+                r#"
+                SELECT file_id, duration, init_id, start_time, media_start, rowid
+                FROM video_files WHERE camera_id = ?1
+                ORDER BY start_time, file_id
+                "#,
+            )?
+            .query_map([&camera_id], |row| Ok((timed_file(row), row.get(5)?)))?
+            .collect::<Result<_, _>>()?;
+
+        let mut sequence = 0;
+        let mut previous: Option<&TimedFile> = None;
+        for (timed, rowid) in &files {
+            let readable = timed.as_ref().ok();
+            let Some(timed) = readable.filter(|timed| checked_end_time(timed).is_some()) else {
+                warn!(
+                    rowid,
+                    "not numbering discontinuities around a malformed file"
+                );
+                update.execute((sequence, rowid))?;
+                continue;
+            };
+            if let Some(previous) = previous {
+                let from = gaps.partition_point(|gap| gap.start_time < previous.start_time);
+                let to = gaps.partition_point(|gap| gap.start_time <= timed.start_time);
+                if is_discontinuity(previous, timed, &gaps[from..to.max(from)]) {
+                    sequence += 1;
+                }
+            }
+            update.execute((sequence, rowid))?;
+            previous = Some(timed);
+        }
+    }
+
+    Ok(())
+}
+
+/// Store the media sequence number of each file, so that they stay contiguous when chunk
+/// sequence numbers are skipped, e.g. for empty chunks that were never indexed. Existing files
+/// are numbered in order, on from the first one's chunk sequence number.
+fn number_media_sequences(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "ALTER TABLE video_files ADD COLUMN media_sequence INTEGER NOT NULL DEFAULT 1",
+    )?;
+
+    renumber_media_sequences(db)
+}
+
+/// Number the files of every camera in order, on from its first one's chunk sequence number.
+fn renumber_media_sequences(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let cameras: Vec<String> = db
+        .prepare("SELECT DISTINCT camera_id FROM video_files")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    let mut update = db.prepare("UPDATE video_files SET media_sequence = ?1 WHERE rowid = ?2")?;
+    for camera_id in cameras {
+        let files: Vec<(Option<String>, i64)> = db
+            .prepare(
+                "SELECT file_id, rowid FROM video_files WHERE camera_id = ?1 ORDER BY start_time, file_id",
+            )?
+            .query_map([&camera_id], |row| Ok((row.get(0).ok(), row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        let first = files
+            .first()
+            .and_then(|(file_id, _)| seq_num(file_id.as_deref()?));
+        for (sequence, (_, rowid)) in (first.unwrap_or(1)..).zip(&files) {
+            update.execute((sequence, rowid))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Move files and gaps that can't be read out of the way, into tables of their own, so that they
+/// can still be looked at but don't break queries. The earlier migrations only skipped over them.
+fn quarantine_malformed_rows(db: &rusqlite::Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        r#"
+        CREATE TABLE malformed_video_files AS SELECT * FROM video_files WHERE 0;
+        CREATE TABLE malformed_gaps AS SELECT * FROM gaps WHERE 0;
+        "#,
+    )?;
+
+    let files: Vec<(bool, i64)> = db
+        .prepare(
+            r#"
+            SELECT file_id, duration, init_id, start_time, media_start, end_time, rowid
+            FROM video_files
+            "#,
+        )?
+        .query_map([], |row| {
+            let readable = timed_file(row).is_ok_and(|timed| checked_end_time(&timed).is_some())
+                && row.get::<_, DateTime<Utc>>(5).is_ok();
+            Ok((readable, row.get(6)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    for (_, rowid) in files.into_iter().filter(|(readable, _)| !readable) {
+        warn!(rowid, "moving malformed file to malformed_video_files");
+        db.execute(
+            "INSERT INTO malformed_video_files SELECT * FROM video_files WHERE rowid = ?1",
+            [rowid],
+        )?;
+        db.execute("DELETE FROM video_files WHERE rowid = ?1", [rowid])?;
+    }
+
+    let gaps: Vec<(bool, i64)> = db
+        .prepare("SELECT start_time, end_time, rowid FROM gaps")?
+        .query_map([], |row| Ok((gap(row).is_ok(), row.get(2)?)))?
+        .collect::<Result<_, _>>()?;
+    for (_, rowid) in gaps.into_iter().filter(|(readable, _)| !readable) {
+        warn!(rowid, "moving malformed gap to malformed_gaps");
+        db.execute(
+            "INSERT INTO malformed_gaps SELECT * FROM gaps WHERE rowid = ?1",
+            [rowid],
+        )?;
+        db.execute("DELETE FROM gaps WHERE rowid = ?1", [rowid])?;
+    }
+
+    // Files are numbered by position, which the files moved out of the way had taken up.
+    renumber_media_sequences(db)
+}
+
+/// End of `timed`, unless its duration is too far out to be real, e.g. in a corrupt legacy row.
+fn checked_end_time(timed: &TimedFile) -> Option<DateTime<Utc>> {
+    let millis = timed.file.duration * 1000.0;
+    if !millis.is_finite() {
+        return None;
+    }
+
+    TimeDelta::try_milliseconds(millis.round() as i64)
+        .and_then(|duration| timed.start_time.checked_add_signed(duration))
+}
+
+fn add_column_if_missing(
+    db: &rusqlite::Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists: bool = db.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        (table, column),
+        |row| row.get(0),
+    )?;
+
+    if !exists {
+        db.execute_batch(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))?;
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use std::ops::Add;
+    use std::path::Path;
     use std::str::FromStr;
 
+    use std::sync::{Arc, Mutex};
+
     use chrono::{DateTime, TimeDelta, Utc};
 
-    use crate::db::Database;
-    use crate::playlist::PlaylistFile;
+    use crate::db::{setup_connection, Database, DbError, SCHEMA_VERSION};
+    use crate::execution::PlaylistBuilder;
+    use crate::playlist::{Gap, PlaylistFile, TimedFile};
+    use crate::upload::queue::UploadStatus;
+
+    const CAMERA: &str = "default";
+
+    #[test]
+    pub fn test_malformed_rows() {
+        let db = Database::memory();
+        db.inner
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO video_files (camera_id, file_id, start_time, end_time, duration) VALUES ('default', '0001.ts', '2000-01-01 00:00:00+00:00', '2000-01-01 00:00:15+00:00', 'long')",
+            )
+            .unwrap();
+
+        assert!(matches!(
+            db.query_timed_files(CAMERA, None, None),
+            Err(DbError::Sqlite(_))
+        ));
+    }
 
     #[test]
     pub fn test_init() {
@@ -121,44 +1027,513 @@ mod test {
         let t3 = t1.add(TimeDelta::seconds(60));
 
         db.append_file(
+            CAMERA,
             t1,
             PlaylistFile {
                 id: "0001.ts".to_string(),
                 duration: 15.16,
+                init: None,
+                media_start: None,
             },
-        );
+        )
+        .unwrap();
         db.append_file(
+            CAMERA,
             t2,
             PlaylistFile {
                 id: "0002.ts".to_string(),
                 duration: 15.16,
+                init: None,
+                media_start: None,
             },
-        );
+        )
+        .unwrap();
         db.append_file(
+            CAMERA,
             t3,
             PlaylistFile {
                 id: "0003.ts".to_string(),
                 duration: 15.16,
+                init: None,
+                media_start: None,
             },
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.query_files(CAMERA, Some(t1), Some(t1.add(TimeDelta::seconds(1))))
+                .unwrap(),
+            vec![file("0001.ts")]
         );
 
-        assert_eq!(db.query_files(Some(t1), Some(t1)), vec![file("0001.ts")]);
+        // Files that started before the range but were still recording are included.
+        assert_eq!(
+            db.query_files(
+                CAMERA,
+                Some(t1.add(TimeDelta::seconds(10))),
+                Some(t2.add(TimeDelta::seconds(1)))
+            )
+            .unwrap(),
+            vec![file("0001.ts"), file("0002.ts")]
+        );
+        assert_eq!(
+            db.query_files(CAMERA, Some(t1.add(TimeDelta::seconds(20))), Some(t2))
+                .unwrap(),
+            vec![]
+        );
 
         assert_eq!(
-            db.query_files(Some(t2), None),
+            db.query_files(CAMERA, Some(t2), None).unwrap(),
             vec![file("0002.ts"), file("0003.ts")]
         );
 
         assert_eq!(
-            db.query_files(None, None,),
+            db.query_files(CAMERA, None, None,).unwrap(),
             vec![file("0001.ts"), file("0002.ts"), file("0003.ts")]
         );
     }
 
+    #[test]
+    pub fn test_latest() {
+        let db = Database::memory();
+
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        for (i, name) in ["0001.ts", "0002.ts", "0003.ts", "0004.ts"]
+            .into_iter()
+            .enumerate()
+        {
+            db.append_file(
+                CAMERA,
+                t1.add(TimeDelta::seconds(15 * i as i64)),
+                file(name),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(
+            db.query_latest_files(CAMERA, 3).unwrap(),
+            vec![file("0002.ts"), file("0003.ts"), file("0004.ts")]
+        );
+        assert_eq!(
+            db.query_latest_files(CAMERA, 10).unwrap(),
+            vec![
+                file("0001.ts"),
+                file("0002.ts"),
+                file("0003.ts"),
+                file("0004.ts")
+            ]
+        );
+        assert_eq!(db.query_latest_files("porch", 3).unwrap(), vec![]);
+        let latest = db.query_latest_timed_files(CAMERA, 3).unwrap();
+        assert_eq!(db.media_sequence(CAMERA, &latest[0]).unwrap(), 2);
+    }
+
+    #[test]
+    pub fn test_cameras_are_separate() {
+        let db = Database::memory();
+
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        db.append_file("porch", t1, file("0001.ts")).unwrap();
+        db.append_file("garage", t1, file("0001.ts")).unwrap();
+        db.append_file("garage", t1, file("0002.ts")).unwrap();
+
+        assert_eq!(
+            db.query_files("porch", None, None).unwrap(),
+            vec![file("0001.ts")]
+        );
+        assert_eq!(
+            db.query_files("garage", None, None).unwrap(),
+            vec![file("0001.ts"), file("0002.ts")]
+        );
+        assert_eq!(db.query_files("driveway", None, None).unwrap(), vec![]);
+    }
+
+    #[test]
+    pub fn test_legacy_rows_belong_to_default_camera() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE video_files (file_id TEXT, start_time DATETIME, duration REAL);
+            INSERT INTO video_files VALUES ('0001.ts', '2000-01-01T00:00:00Z', 15.16);
+            "#,
+        )
+        .unwrap();
+        setup_connection(&mut conn).unwrap();
+
+        let db = Database {
+            inner: Arc::new(Mutex::new(conn)),
+        };
+        assert_eq!(
+            db.query_files(CAMERA, None, None).unwrap(),
+            vec![file("0001.ts")]
+        );
+    }
+
+    fn user_version(conn: &rusqlite::Connection) -> u32 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    pub fn test_migrate_v0() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE video_files (file_id TEXT, start_time DATETIME, duration REAL);
+            INSERT INTO video_files VALUES ('0001.ts', '2000-01-01T00:00:00Z', 15.16);
+            INSERT INTO video_files VALUES ('0002.ts', '2000-01-01T00:01:00Z', 15.16);
+            "#,
+        )
+        .unwrap();
+        assert_eq!(user_version(&conn), 0);
+
+        setup_connection(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+
+        // Migrating again is a no-op.
+        setup_connection(&mut conn).unwrap();
+        let db = Database {
+            inner: Arc::new(Mutex::new(conn)),
+        };
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let files = db.query_timed_files(CAMERA, None, None).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                TimedFile {
+                    start_time: t1,
+                    file: file("0001.ts")
+                },
+                TimedFile {
+                    start_time: t1.add(TimeDelta::seconds(60)),
+                    file: file("0002.ts")
+                }
+            ]
+        );
+        // Existing files had their discontinuities numbered.
+        assert_eq!(db.discontinuity_sequence(CAMERA, &files[0]).unwrap(), 0);
+        assert_eq!(db.discontinuity_sequence(CAMERA, &files[1]).unwrap(), 1);
+        // And their media sequence numbers, on from the first chunk's.
+        assert_eq!(db.media_sequence(CAMERA, &files[0]).unwrap(), 1);
+        assert_eq!(db.media_sequence(CAMERA, &files[1]).unwrap(), 2);
+        // The end of the file was filled in.
+        assert_eq!(
+            db.query_files(
+                CAMERA,
+                Some(t1.add(TimeDelta::seconds(15))),
+                Some(t1.add(TimeDelta::seconds(20)))
+            )
+            .unwrap(),
+            vec![file("0001.ts")]
+        );
+        db.enqueue_upload(CAMERA, "0001.ts", Path::new("recordings/0001.ts"), t1)
+            .unwrap();
+    }
+
+    #[test]
+    pub fn test_migrate_malformed() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE video_files (file_id TEXT, start_time DATETIME, duration REAL);
+            INSERT INTO video_files VALUES ('0001.ts', '2000-01-01T00:00:00Z', 15.16);
+            INSERT INTO video_files VALUES ('0002.ts', '2000-01-01T00:00:15Z', 'long');
+            INSERT INTO video_files VALUES ('0003.ts', 'yesterday', 15.16);
+            INSERT INTO video_files VALUES ('0004.ts', '2000-01-01T00:00:30Z', 15.16);
+            "#,
+        )
+        .unwrap();
+
+        setup_connection(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+
+        // The malformed files were moved out of the way, the one with a malformed duration
+        // ending where it starts.
+        let (start_time, end_time): (String, String) = conn
+            .query_row(
+                "SELECT start_time, end_time FROM malformed_video_files WHERE file_id = '0002.ts'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(start_time, end_time);
+        let malformed: u64 = conn
+            .query_row("SELECT COUNT(*) FROM malformed_video_files", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(malformed, 2);
+
+        // Numbering skipped over the malformed files.
+        let db = Database {
+            inner: Arc::new(Mutex::new(conn)),
+        };
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let last = TimedFile {
+            start_time: t1.add(TimeDelta::seconds(30)),
+            file: file("0004.ts"),
+        };
+        assert_eq!(db.discontinuity_sequence(CAMERA, &last).unwrap(), 1);
+        assert_eq!(db.media_sequence(CAMERA, &last).unwrap(), 2);
+
+        // Recording carries on after them.
+        db.append_file(CAMERA, t1.add(TimeDelta::seconds(45)), file("0005.ts"))
+            .unwrap();
+        let live = PlaylistBuilder::new(&db).build_live(CAMERA).unwrap();
+        assert_eq!(live.media_sequence, 1);
+        assert_eq!(live.files.len(), 3);
+        assert!(live.render().contains("0005.ts"));
+    }
+
+    #[test]
+    pub fn test_migrate_unversioned() {
+        // Built before schema versions, with some of the later columns already added.
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE video_files (
+                file_id TEXT,
+                start_time DATETIME,
+                duration REAL,
+                camera_id TEXT NOT NULL DEFAULT 'default'
+            );
+            CREATE TABLE gaps (camera_id TEXT NOT NULL, start_time DATETIME, end_time DATETIME);
+            "#,
+        )
+        .unwrap();
+
+        setup_connection(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+    }
+
+    #[test]
+    pub fn test_refuse_newer_schema() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+
+        let error = setup_connection(&mut conn).unwrap_err();
+        assert!(matches!(error, DbError::TooNew { found } if found == SCHEMA_VERSION + 1));
+    }
+
+    #[test]
+    pub fn test_init_segments() {
+        let db = Database::memory();
+
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        db.append_file(
+            CAMERA,
+            t1,
+            PlaylistFile {
+                id: "000000001.m4s".to_string(),
+                duration: 15.0,
+                init: Some("init-0123456789abcdef.mp4".to_string()),
+                media_start: Some(3600.5),
+            },
+        )
+        .unwrap();
+
+        let files = db.query_files(CAMERA, None, None).unwrap();
+        assert_eq!(files[0].init.as_deref(), Some("init-0123456789abcdef.mp4"));
+        assert_eq!(files[0].media_start, Some(3600.5));
+    }
+
+    #[test]
+    pub fn test_overlapping() {
+        let db = Database::memory();
+
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        for (i, name) in ["0001.ts", "0002.ts", "0003.ts", "0004.ts"]
+            .into_iter()
+            .enumerate()
+        {
+            db.append_file(
+                CAMERA,
+                t1.add(TimeDelta::seconds(15 * i as i64)),
+                file(name),
+            )
+            .unwrap();
+        }
+
+        let ids = |files: Vec<TimedFile>| -> Vec<String> {
+            files.into_iter().map(|timed| timed.file.id).collect()
+        };
+        assert_eq!(
+            ids(db
+                .query_timed_files(
+                    CAMERA,
+                    Some(t1.add(TimeDelta::seconds(20))),
+                    Some(t1.add(TimeDelta::seconds(35)))
+                )
+                .unwrap()),
+            vec!["0002.ts", "0003.ts"]
+        );
+        assert!(db
+            .query_timed_files(
+                CAMERA,
+                Some(t1.add(TimeDelta::seconds(90))),
+                Some(t1.add(TimeDelta::seconds(100)))
+            )
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    pub fn test_gaps() {
+        let db = Database::memory();
+
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let t2 = t1.add(TimeDelta::seconds(30));
+        db.append_gap(CAMERA, t1, t2).unwrap();
+
+        assert_eq!(
+            db.query_gaps(CAMERA, t1, t2).unwrap(),
+            vec![Gap {
+                start_time: t1,
+                end_time: t2
+            }]
+        );
+        assert_eq!(
+            db.query_overlapping_gaps(
+                CAMERA,
+                t1 + TimeDelta::seconds(10),
+                t2 + TimeDelta::seconds(10)
+            )
+            .unwrap()
+            .len(),
+            1
+        );
+        assert!(db
+            .query_overlapping_gaps(
+                CAMERA,
+                t2 + TimeDelta::seconds(1),
+                t2 + TimeDelta::seconds(10)
+            )
+            .unwrap()
+            .is_empty());
+
+        let db = db.inner.lock().unwrap();
+        let (start, end): (DateTime<Utc>, DateTime<Utc>) = db
+            .query_row("SELECT start_time, end_time FROM gaps", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+
+        assert_eq!((start, end), (t1, t2));
+    }
+
+    #[test]
+    pub fn test_replace_index() {
+        let db = Database::memory();
+
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        db.append_file(CAMERA, t1, file("0001.ts")).unwrap();
+        db.append_file("porch", t1, file("0001.ts")).unwrap();
+        db.append_gap(CAMERA, t1, t1.add(TimeDelta::seconds(30)))
+            .unwrap();
+
+        let gap = Gap {
+            start_time: t1.add(TimeDelta::seconds(60)),
+            end_time: t1.add(TimeDelta::seconds(90)),
+        };
+        db.replace_index(
+            CAMERA,
+            &[
+                TimedFile {
+                    start_time: t1.add(TimeDelta::seconds(45)),
+                    file: file("0002.ts"),
+                },
+                TimedFile {
+                    start_time: t1.add(TimeDelta::seconds(90)),
+                    file: file("0003.ts"),
+                },
+            ],
+            std::slice::from_ref(&gap),
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.query_files(CAMERA, None, None).unwrap(),
+            vec![file("0002.ts"), file("0003.ts")]
+        );
+        assert_eq!(
+            db.query_overlapping_gaps(CAMERA, t1, t1.add(TimeDelta::hours(1)))
+                .unwrap(),
+            vec![gap]
+        );
+        // Other cameras are left alone.
+        assert_eq!(
+            db.query_files("porch", None, None).unwrap(),
+            vec![file("0001.ts")]
+        );
+    }
+
+    #[test]
+    pub fn test_upload_queue() {
+        let db = Database::memory();
+
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let t2 = t1.add(TimeDelta::seconds(30));
+        db.enqueue_upload(CAMERA, "0001.ts", Path::new("recordings/0001.ts"), t1)
+            .unwrap();
+        db.enqueue_upload(CAMERA, "0002.ts", Path::new("recordings/0002.ts"), t1)
+            .unwrap();
+        assert_eq!(
+            db.upload_status(CAMERA, "0001.ts").unwrap(),
+            Some(UploadStatus::Pending)
+        );
+        assert_eq!(db.next_upload_due().unwrap(), Some(t1));
+        assert_eq!(db.count_unfinished_uploads().unwrap(), 2);
+
+        let task = db.claim_upload(t1).unwrap().unwrap();
+        assert_eq!(task.file_id, "0001.ts");
+        assert_eq!(task.path, Path::new("recordings/0001.ts"));
+        assert_eq!(
+            db.upload_status(CAMERA, "0001.ts").unwrap(),
+            Some(UploadStatus::InProgress)
+        );
+        db.complete_upload(CAMERA, "0001.ts").unwrap();
+        assert_eq!(db.count_unfinished_uploads().unwrap(), 1);
+
+        // Failed uploads aren't retried before they're due.
+        let task = db.claim_upload(t1).unwrap().unwrap();
+        assert_eq!(task.file_id, "0002.ts");
+        db.retry_upload(CAMERA, "0002.ts", "storage is unavailable", t2)
+            .unwrap();
+        assert_eq!(db.claim_upload(t1).unwrap(), None);
+        assert_eq!(db.next_upload_due().unwrap(), Some(t2));
+
+        let task = db.claim_upload(t2).unwrap().unwrap();
+        assert_eq!(task.attempts, 1);
+
+        // A restart puts interrupted uploads back in the queue, but never re-uploads finished ones.
+        db.reset_interrupted_uploads(CAMERA).unwrap();
+        db.enqueue_upload(CAMERA, "0001.ts", Path::new("recordings/0001.ts"), t2)
+            .unwrap();
+        assert_eq!(
+            db.upload_status(CAMERA, "0001.ts").unwrap(),
+            Some(UploadStatus::Done)
+        );
+        assert_eq!(
+            db.upload_status(CAMERA, "0002.ts").unwrap(),
+            Some(UploadStatus::Pending)
+        );
+
+        db.fail_upload(CAMERA, "0002.ts", "chunk is missing")
+            .unwrap();
+        assert_eq!(
+            db.upload_status(CAMERA, "0002.ts").unwrap(),
+            Some(UploadStatus::Failed)
+        );
+        assert_eq!(db.next_upload_due().unwrap(), None);
+    }
+
     fn file(name: &'static str) -> PlaylistFile {
         PlaylistFile {
             id: name.to_string(),
             duration: 15.16,
+            init: None,
+            media_start: None,
         }
     }
 }
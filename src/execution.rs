@@ -1,41 +1,122 @@
+use std::collections::VecDeque;
 use std::ops::Mul;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::time::Duration;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use ffmpeg_next::codec::Parameters;
 use ffmpeg_next::format::context::Input;
 use ffmpeg_next::media::Type;
-use ffmpeg_next::{format, Rational};
-use tokio::io::AsyncReadExt;
-use tokio::runtime::Handle;
-use tracing::{error, info, warn};
+use ffmpeg_next::{ffi, format, Dictionary, Packet, Rational};
+use tracing::{debug, error, info, info_span, warn};
 
+use crate::backoff::Backoff;
+use crate::camera::Camera;
 use crate::chunk::{ChunkWriter, ChunkWriterFactory};
-use crate::db::Database;
-use crate::playlist::{OnDemandTimeRange, Playlist, PlaylistFile, PlaylistKind};
-use crate::upload::Uploader;
+use crate::clock::{ClockSource, StreamClock, MAX_RTCP_SKEW};
+use crate::dash::Manifest;
+use crate::db::{Database, DbError};
+use crate::metrics::Metrics;
+use crate::playlist::{Gap, OnDemandTimeRange, Playlist, PlaylistFile, PlaylistKind, TimedFile};
+use crate::shutdown::Shutdown;
+use crate::timeline::Timeline;
+use crate::upload::queue::UploadQueue;
+
+/// Seconds a chunk may run past `roll_seconds` waiting for a keyframe, unless configured with
+/// [`Pipeline::with_max_roll_overshoot_seconds`].
+pub const DEFAULT_MAX_ROLL_OVERSHOOT_SECONDS: u32 = 10;
 
 pub struct Pipeline {
+    camera_id: String,
+    url: String,
+    roll_seconds: u32,
+    max_roll_overshoot_seconds: u32,
+    read_timeout: Duration,
+    reconnect_backoff: Backoff,
+    shutdown: Shutdown,
+    pending_index: PendingIndex,
+    metrics: Metrics,
+}
+
+/// Index writes that failed, e.g. because the database was locked or the disk full. They're
+/// kept in order and retried before every later write, so a database outage doesn't stop the
+/// recording.
+#[derive(Default)]
+struct PendingIndex {
+    writes: VecDeque<IndexWrite>,
+}
+
+enum IndexWrite {
+    File {
+        start_time: DateTime<Utc>,
+        file: PlaylistFile,
+    },
+    Gap {
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    },
+}
+
+impl PendingIndex {
+    /// Write `write` after everything still pending, buffering it if that fails.
+    fn push(&mut self, database: &Database, camera_id: &str, write: IndexWrite) {
+        self.writes.push_back(write);
+        self.flush(database, camera_id);
+    }
+
+    /// Retry the pending writes in order, stopping at the first one that fails again.
+    fn flush(&mut self, database: &Database, camera_id: &str) {
+        while let Some(write) = self.writes.front() {
+            let result = match write {
+                IndexWrite::File { start_time, file } => {
+                    database.append_file(camera_id, *start_time, file.clone())
+                }
+                IndexWrite::Gap {
+                    start_time,
+                    end_time,
+                } => database.append_gap(camera_id, *start_time, *end_time),
+            };
+
+            if let Err(e) = result {
+                warn!(
+                    error = %e,
+                    pending = self.writes.len(),
+                    "failed to write to the database, retrying with the next chunk"
+                );
+                return;
+            }
+            self.writes.pop_front();
+        }
+    }
+}
+
+/// An open connection to the input stream. Dropped and reopened by the pipeline whenever the
+/// stream ends or fails.
+struct Source {
     input_context: Input,
     audio_index: Option<usize>,
     video_parameters: Parameters,
     audio_parameters: Option<Parameters>,
     index_mapping: Vec<usize>,
-    roll_seconds: u32,
-    background_tasks: Handle,
+    time_bases: Vec<Rational>,
+    rtcp_rejected: bool,
 }
 
-impl Pipeline {
-    /// Create a pipeline from an input stream (could be RTSP, file, MPEG-TS, etc.).
-    /// Provide it with a chunk writer factory as well. So that way we can
-    pub fn from<S: AsRef<str>>(url: S, background_tasks: Handle) -> Self {
-        let input_context = format::input(&String::from(url.as_ref())).expect("open input format");
+impl Source {
+    fn open(url: &str, read_timeout: Duration) -> Result<Self, ffmpeg_next::Error> {
+        // Without a socket timeout a camera that silently drops off the network blocks the
+        // read forever instead of surfacing an error we can reconnect from.
+        let timeout_us = read_timeout.as_micros().to_string();
+        let mut options = Dictionary::new();
+        options.set("timeout", &timeout_us);
+        options.set("rw_timeout", &timeout_us);
+
+        let input_context = format::input_with_dictionary(&String::from(url), options)?;
 
         let video_index = input_context
             .streams()
             .best(Type::Video)
-            .expect("expected input to contain at least one video stream")
+            .ok_or(ffmpeg_next::Error::StreamNotFound)?
             .index();
 
         let audio_index = input_context
@@ -62,176 +143,876 @@ impl Pipeline {
             index_mapping[audio_index] = 1;
         }
 
-        Self {
+        let time_bases = input_context
+            .streams()
+            .map(|stream| stream.time_base())
+            .collect();
+
+        Ok(Self {
             input_context,
             audio_index,
             video_parameters,
             audio_parameters,
             index_mapping,
-            background_tasks,
+            time_bases,
+            rtcp_rejected: false,
+        })
+    }
+
+    /// Wall-clock time of presentation timestamp 0 in microseconds since the Unix epoch, known
+    /// once an RTSP source has received its first RTCP sender report.
+    fn rtcp_start_time_us(&self) -> Option<i64> {
+        // SAFETY: the pointer is valid for as long as the input context is.
+        let start_time_realtime = unsafe { (*self.input_context.as_ptr()).start_time_realtime };
+
+        (start_time_realtime != ffi::AV_NOPTS_VALUE).then_some(start_time_realtime)
+    }
+
+    /// Clock for the frames of this connection, anchored at the video frame at `pts`.
+    fn clock(&mut self, pts: i64, time_base: Rational) -> StreamClock {
+        let now = Utc::now();
+
+        self.rtcp_clock(pts, time_base, now)
+            .unwrap_or_else(|| StreamClock::system(pts, time_base, now))
+    }
+
+    /// Clock from the RTCP sender reports, once the first one has arrived. If it's too far off
+    /// the system clock, it's ignored for the rest of the connection.
+    fn rtcp_clock(
+        &mut self,
+        pts: i64,
+        time_base: Rational,
+        now: DateTime<Utc>,
+    ) -> Option<StreamClock> {
+        if self.rtcp_rejected {
+            return None;
+        }
+
+        let start_time_us = self.rtcp_start_time_us()?;
+        let clock = StreamClock::rtcp(start_time_us, pts, time_base, now);
+        if clock.is_none() {
+            warn!("RTCP time is more than {MAX_RTCP_SKEW} off the system clock, using the system clock instead");
+            self.rtcp_rejected = true;
+        }
+
+        clock
+    }
+}
+
+impl Pipeline {
+    /// Create a pipeline from an input stream (could be RTSP, file, MPEG-TS, etc.).
+    ///
+    /// The stream isn't opened until [`Pipeline::run`], which keeps reconnecting to it for as long
+    /// as the pipeline runs.
+    pub fn from<S: AsRef<str>>(url: S) -> Self {
+        Self {
+            camera_id: Camera::DEFAULT_ID.to_string(),
+            url: url.as_ref().to_string(),
             roll_seconds: 10,
+            max_roll_overshoot_seconds: DEFAULT_MAX_ROLL_OVERSHOOT_SECONDS,
+            read_timeout: Duration::from_secs(10),
+            reconnect_backoff: Backoff::default(),
+            shutdown: Shutdown::new(),
+            pending_index: PendingIndex::default(),
+            metrics: Metrics::new(),
         }
     }
 
+    /// Create a pipeline recording `camera`, indexing its chunks under the camera's id.
+    pub fn for_camera(camera: &Camera) -> Self {
+        let mut pipeline = Self::from(&camera.source);
+        pipeline.camera_id = camera.id.clone();
+
+        pipeline
+    }
+
     pub fn with_roll_seconds(mut self, new_roll_seconds: u32) -> Self {
         self.roll_seconds = new_roll_seconds;
 
         self
     }
 
-    pub fn run<F: ChunkWriterFactory, U: Uploader + 'static>(
+    /// Chunks are only rolled on a keyframe once `roll_seconds` have elapsed. If the camera's GOP
+    /// is so long that no keyframe arrives within this many extra seconds, roll on whatever packet
+    /// comes next instead.
+    pub fn with_max_roll_overshoot_seconds(mut self, max_roll_overshoot_seconds: u32) -> Self {
+        self.max_roll_overshoot_seconds = max_roll_overshoot_seconds;
+
+        self
+    }
+
+    /// How long a read from the input may block before the stream is considered dead.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+
+        self
+    }
+
+    /// Delay between attempts to reopen the input, doubling from `initial` up to `max`.
+    pub fn with_reconnect_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.reconnect_backoff = Backoff::new(initial, max);
+
+        self
+    }
+
+    /// Stop recording once `shutdown` is requested. The chunk being recorded is closed and
+    /// queued for upload like any other.
+    ///
+    /// Shutdown is checked between packets, so a stalled input delays it by up to the read
+    /// timeout.
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = shutdown.clone();
+
+        self
+    }
+
+    /// Report the drift of the camera's clock to `metrics`.
+    pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        self.metrics = metrics.clone();
+
+        self
+    }
+
+    /// Record the input until shutdown, reopening it with exponential backoff whenever it ends or
+    /// fails. Time spent disconnected is recorded in the database as a gap.
+    pub fn run<F: ChunkWriterFactory>(
         &mut self,
         chunk_writers: &mut F,
-        chunk_uploader: Arc<U>,
+        uploads: &UploadQueue,
         database: &Database,
     ) {
+        let _span = info_span!("pipeline", camera = %self.camera_id).entered();
         info!("begin pipeline");
+        let mut disconnected_at: Option<DateTime<Utc>> = None;
+
+        while !self.shutdown.is_requested() {
+            let mut source = match Source::open(&self.url, self.read_timeout) {
+                Ok(source) => source,
+                Err(e) => {
+                    let delay = self.reconnect_backoff.next_delay();
+                    warn!(error = %e, "failed to open input, retrying in {delay:?}");
+                    self.shutdown.sleep(delay);
+                    continue;
+                }
+            };
+            self.reconnect_backoff.reset();
+
+            if let Some(disconnected_at) = disconnected_at.take() {
+                let reconnected_at = Utc::now();
+                info!(
+                    "input reconnected after {}",
+                    reconnected_at - disconnected_at
+                );
+                self.pending_index.push(
+                    database,
+                    &self.camera_id,
+                    IndexWrite::Gap {
+                        start_time: disconnected_at,
+                        end_time: reconnected_at,
+                    },
+                );
+            }
+
+            self.record(&mut source, chunk_writers, uploads, database);
+            if self.shutdown.is_requested() {
+                break;
+            }
+
+            warn!("input disconnected, reconnecting");
+            disconnected_at = Some(Utc::now());
+        }
+
+        if let Some(disconnected_at) = disconnected_at {
+            self.pending_index.push(
+                database,
+                &self.camera_id,
+                IndexWrite::Gap {
+                    start_time: disconnected_at,
+                    end_time: Utc::now(),
+                },
+            );
+        }
+        if !self.pending_index.writes.is_empty() {
+            error!(
+                lost = self.pending_index.writes.len(),
+                "database still unavailable at shutdown, the last chunks are missing from the index"
+            );
+        }
+        info!("end pipeline");
+    }
+
+    /// Record chunks from `source` until it reaches EOF, a read fails or shutdown is requested.
+    fn record<F: ChunkWriterFactory>(
+        &mut self,
+        source: &mut Source,
+        chunk_writers: &mut F,
+        uploads: &UploadQueue,
+        database: &Database,
+    ) {
         let mut chunk_writer = chunk_writers.next();
-        let metadata = self.input_context.metadata().to_owned().clone();
+        let metadata = source.input_context.metadata().to_owned().clone();
         chunk_writer.begin(
             &metadata,
-            self.video_parameters.clone(),
-            self.audio_parameters.clone(),
+            source.video_parameters.clone(),
+            source.audio_parameters.clone(),
         );
 
         let video_packets = AtomicU64::new(0);
         let audio_packets = AtomicU64::new(0);
         let unknown_packets = AtomicU64::new(0);
+        let skipped_packets = AtomicU64::new(0);
+
+        // Set by the first keyframe, see [`crate::clock`].
+        let mut clock: Option<StreamClock> = None;
+        let mut span = ChunkSpan::default();
+        let mut awaiting_keyframe = true;
+        loop {
+            if self.shutdown.is_requested() {
+                info!("shutdown requested, closing the current chunk");
+                break;
+            }
 
-        // spawn a background task to upload the chunk
-        let mut current_chunk_start = Utc::now();
+            let mut packet = Packet::empty();
+            match packet.read(&mut source.input_context) {
+                Ok(()) => {}
+                Err(ffmpeg_next::Error::Eof) => {
+                    info!("input reached end of stream");
+                    break;
+                }
+                Err(ffmpeg_next::Error::InvalidData) => {
+                    // A single corrupt packet, the stream itself is still alive.
+                    continue;
+                }
+                Err(e) => {
+                    warn!(error = %e, "failed to read from input");
+                    break;
+                }
+            }
 
-        let mut start_pts = -1;
-        for (stream, packet) in self.input_context.packets() {
-            let out_index = self.index_mapping[stream.index()];
+            let in_index = packet.stream();
+            let time_base = source.time_bases[in_index];
+            let out_index = source.index_mapping[in_index];
 
-            let should_roll = match out_index {
+            match out_index {
                 _ if out_index == 0 => {
-                    let pts = packet.pts().unwrap_or_default();
-                    chunk_writer.write_video(packet, stream.time_base());
-                    video_packets.fetch_add(1, Ordering::SeqCst);
+                    let pts = packet.pts().or(packet.dts()).unwrap_or_default();
+                    let duration = packet.duration();
+                    let is_key = packet.is_key();
+
+                    // Chunks need to start on a keyframe to be playable on their own, so
+                    // anything before the first keyframe of a connection is dropped.
+                    if awaiting_keyframe {
+                        if !is_key {
+                            skipped_packets.fetch_add(1, Ordering::SeqCst);
+                            continue;
+                        }
+                        awaiting_keyframe = false;
 
-                    if start_pts < 0 {
-                        start_pts = pts;
-                        false
-                    } else {
-                        // check if we should roll
-                        should_roll(start_pts, pts, stream.time_base(), self.roll_seconds)
+                        let started = source.clock(pts, time_base);
+                        info!(source = started.source().as_str(), "clock started");
+                        clock = Some(started);
                     }
+
+                    // check if we should roll, so the packet that triggered it opens the next chunk
+                    if !span.is_empty()
+                        && should_roll(
+                            span.start_pts(),
+                            pts,
+                            time_base,
+                            is_key,
+                            self.roll_seconds,
+                            self.max_roll_overshoot_seconds,
+                        )
+                    {
+                        if is_key {
+                            info!("rolling output file");
+                        } else {
+                            warn!("no keyframe within the maximum overshoot, rolling output file on a non-keyframe");
+                        }
+                        let chunk_clock = clock.expect("clock starts with the first keyframe");
+                        self.finish_chunk(
+                            &mut chunk_writer,
+                            &chunk_clock,
+                            &span,
+                            uploads,
+                            database,
+                        );
+
+                        if chunk_clock.source() == ClockSource::System {
+                            if let Some(rtcp) = source.rtcp_clock(pts, time_base, Utc::now()) {
+                                info!("received an RTCP sender report, switching to its clock");
+                                let handed_over = chunk_clock.hand_over(rtcp, span.end_pts());
+                                if handed_over != rtcp {
+                                    let behind = handed_over.time_at(pts) - rtcp.time_at(pts);
+                                    warn!("RTCP time is {behind} behind the chunks recorded so far, moving it forward to meet them");
+                                }
+                                clock = Some(handed_over);
+                            }
+                        }
+                        span = ChunkSpan::default();
+
+                        chunk_writer = chunk_writers.next();
+                        chunk_writer.begin(
+                            &metadata,
+                            source.video_parameters.clone(),
+                            source.audio_parameters.clone(),
+                        );
+                    }
+
+                    chunk_writer.write_video(packet, time_base);
+                    video_packets.fetch_add(1, Ordering::SeqCst);
+
+                    span.push(pts, duration, time_base);
                 }
-                _ if out_index == 1 && self.audio_index.is_some() => {
-                    chunk_writer.write_audio(packet, stream.time_base());
+                _ if out_index == 1 && source.audio_index.is_some() => {
+                    if awaiting_keyframe {
+                        skipped_packets.fetch_add(1, Ordering::SeqCst);
+                        continue;
+                    }
+                    chunk_writer.write_audio(packet, time_base);
                     audio_packets.fetch_add(1, Ordering::SeqCst);
-                    false
                 }
                 _ => {
                     unknown_packets.fetch_add(1, Ordering::SeqCst);
-                    false
                 }
-            };
+            }
+        }
 
-            if should_roll {
-                info!("rolling output file");
+        // Keep whatever was recorded before the input went away.
+        match clock {
+            Some(clock) if !span.is_empty() => {
+                self.finish_chunk(&mut chunk_writer, &clock, &span, uploads, database);
+            }
+            _ => {
                 let file_path = chunk_writer.end();
-                start_pts = -1;
-
-                // Update DB with new file
-                database.append_file(
-                    current_chunk_start,
-                    PlaylistFile {
-                        duration: 15.16, // TODO(aduffy) this is hard-coded, bad.
-                        id: file_path.file_name().unwrap().to_str().unwrap().to_string(),
-                    },
-                );
-
-                // spawn upload task
-                let chunk_uploader = Arc::clone(&chunk_uploader);
-                self.background_tasks.spawn(async move {
-                    for attempt in 0..10 {
-                        let chunk_uploader = Arc::clone(&chunk_uploader);
+                std::fs::remove_file(&file_path).ok();
+            }
+        }
 
-                        match background_upload(file_path.clone(), chunk_uploader).await {
-                            Ok(()) => {
-                                return;
-                            }
-                            Err(e) => {
-                                warn!(error = %e, "upload attempt {attempt} of 10 failed");
-                                continue;
-                            }
-                        }
-                    }
+        info!(
+            video_packets = video_packets.load(Ordering::Relaxed),
+            audio_packets = audio_packets.load(Ordering::Relaxed),
+            other_packets = unknown_packets.load(Ordering::Relaxed),
+            skipped_packets = skipped_packets.load(Ordering::Relaxed),
+            "processed packets"
+        );
+    }
 
-                    error!("10 failed attempts for upload task {file_path:?}, failing upload");
-                });
+    /// Close the chunk, add it to the database and queue it for upload.
+    fn finish_chunk<W: ChunkWriter>(
+        &mut self,
+        chunk_writer: &mut W,
+        clock: &StreamClock,
+        span: &ChunkSpan,
+        uploads: &UploadQueue,
+        database: &Database,
+    ) {
+        let file_path = chunk_writer.end();
+        let init_segment = chunk_writer.init_segment();
+
+        let drift = clock.drift(span.end_pts(), Utc::now());
+        debug!(source = clock.source().as_str(), "clock drifted {drift}");
+        self.metrics
+            .record_clock_drift(&self.camera_id, clock.source(), drift);
+
+        // Update DB with new file
+        self.pending_index.push(
+            database,
+            &self.camera_id,
+            IndexWrite::File {
+                start_time: clock.time_at(span.start_pts()),
+                file: PlaylistFile {
+                    duration: span.duration_seconds(),
+                    id: file_path.file_name().unwrap().to_str().unwrap().to_string(),
+                    init: init_segment
+                        .as_ref()
+                        .map(|path| path.file_name().unwrap().to_str().unwrap().to_string()),
+                    media_start: Some(span.start_seconds()),
+                },
+            },
+        );
 
-                chunk_writer = chunk_writers.next();
-                chunk_writer.begin(
-                    &metadata,
-                    self.video_parameters.clone(),
-                    self.audio_parameters.clone(),
-                );
-                current_chunk_start = Utc::now();
+        // Init segments are shared between chunks, only queue them the first time around.
+        if let Some(init_segment) = &init_segment {
+            let init_id = init_segment.file_name().unwrap().to_str().unwrap();
+            if !matches!(
+                database.upload_status(&self.camera_id, init_id),
+                Ok(Some(_))
+            ) {
+                uploads.enqueue(&self.camera_id, init_segment);
             }
         }
+        uploads.enqueue(&self.camera_id, &file_path);
+    }
+}
 
-        chunk_writer.end();
+/// Span of video timestamps written into a chunk, used to compute its real duration.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ChunkSpan {
+    time_base: Option<Rational>,
+    start_pts: i64,
+    end_pts: i64,
+    last_pts: i64,
+    last_interval: i64,
+}
 
-        println!(
-            "Processing Statistics: video={} audio={} other={}",
-            video_packets.fetch_add(0, Ordering::Relaxed),
-            audio_packets.fetch_add(0, Ordering::Relaxed),
-            unknown_packets.fetch_add(0, Ordering::Relaxed),
-        );
+impl ChunkSpan {
+    /// Add a video packet to the span. Packets that don't carry a duration are assumed to last as
+    /// long as the interval between the previous two frames.
+    pub(crate) fn push(&mut self, pts: i64, duration: i64, time_base: Rational) {
+        if self.time_base.is_none() {
+            self.time_base = Some(time_base);
+            self.start_pts = pts;
+            self.end_pts = pts;
+        } else if pts > self.last_pts {
+            self.last_interval = pts - self.last_pts;
+        }
+        self.last_pts = pts;
+
+        let duration = if duration > 0 {
+            duration
+        } else {
+            self.last_interval
+        };
+        // B-frames arrive out of presentation order, so the span ends at the latest frame seen
+        // rather than the last one written.
+        self.end_pts = self.end_pts.max(pts + duration);
+        self.start_pts = self.start_pts.min(pts);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.time_base.is_none()
+    }
+
+    pub(crate) fn start_pts(&self) -> i64 {
+        self.start_pts
+    }
+
+    pub(crate) fn end_pts(&self) -> i64 {
+        self.end_pts
     }
-}
 
-async fn background_upload<U: Uploader>(
-    file_path: impl AsRef<std::path::Path>,
-    uploader: Arc<U>,
-) -> anyhow::Result<()> {
-    let mut chunk = Vec::new();
-    let mut file = tokio::fs::File::open(file_path.as_ref())
-        .await
-        .expect("open file for upload");
-    file.read_to_end(&mut chunk)
-        .await
-        .expect("read file for upload");
-
-    let file_name = file_path
-        .as_ref()
-        .file_name()
-        .expect("file_name")
-        .to_str()
-        .expect("file_name to str");
-    uploader.upload_chunk(file_name, chunk).await
+    /// Presentation time of the first frame, in seconds.
+    pub(crate) fn start_seconds(&self) -> f64 {
+        match self.time_base {
+            Some(time_base) => {
+                self.start_pts as f64 * time_base.numerator() as f64
+                    / time_base.denominator() as f64
+            }
+            None => 0.0,
+        }
+    }
+
+    pub(crate) fn duration_seconds(&self) -> f64 {
+        match self.time_base {
+            Some(time_base) => {
+                (self.end_pts - self.start_pts) as f64 * time_base.numerator() as f64
+                    / time_base.denominator() as f64
+            }
+            None => 0.0,
+        }
+    }
 }
 
-fn should_roll(start_pts: i64, current_pts: i64, time_base: Rational, roll_seconds: u32) -> bool {
+fn should_roll(
+    start_pts: i64,
+    current_pts: i64,
+    time_base: Rational,
+    is_key: bool,
+    roll_seconds: u32,
+    max_overshoot_seconds: u32,
+) -> bool {
     let delta = current_pts - start_pts;
+    let elapsed = Rational(delta as _, 1).mul(time_base);
 
-    Rational(delta as _, 1).mul(time_base) >= Rational(roll_seconds as _, 1)
+    if is_key {
+        elapsed >= Rational(roll_seconds as _, 1)
+    } else {
+        elapsed >= Rational((roll_seconds + max_overshoot_seconds) as _, 1)
+    }
 }
 
 #[derive(Clone)]
 pub struct PlaylistBuilder {
     db: Database,
+    live_window: usize,
+    max_chunk_seconds: Option<u64>,
 }
 
 impl PlaylistBuilder {
     pub fn new(db: &Database) -> Self {
-        Self { db: db.clone() }
+        Self {
+            db: db.clone(),
+            live_window: 5,
+            max_chunk_seconds: None,
+        }
+    }
+
+    /// Number of the most recent files to include in live playlists.
+    pub fn with_live_window(mut self, live_window: usize) -> Self {
+        self.live_window = live_window;
+
+        self
+    }
+
+    /// Longest the recorded chunks can be, which live playlists use as their target duration.
+    /// Without it, they fall back to the longest file in the window, which can change as the
+    /// window slides.
+    pub fn with_max_chunk_seconds(mut self, max_chunk_seconds: u32) -> Self {
+        self.max_chunk_seconds = Some(max_chunk_seconds.into());
+
+        self
     }
 }
 
 impl PlaylistBuilder {
-    pub fn build_on_demand(&self, time_range: OnDemandTimeRange) -> Playlist {
+    pub fn build_on_demand(
+        &self,
+        camera_id: &str,
+        time_range: OnDemandTimeRange,
+    ) -> Result<Playlist, DbError> {
+        let files =
+            self.db
+                .query_timed_files(camera_id, Some(time_range.start), Some(time_range.end))?;
+
+        let media_sequence = match files.first() {
+            Some(first) => self.db.media_sequence(camera_id, first)?,
+            None => 1,
+        };
+
+        Ok(Playlist {
+            kind: PlaylistKind::VOD,
+            media_sequence,
+            discontinuity_sequence: 0,
+            gaps: self.gaps_between(camera_id, &files)?,
+            files,
+            max_duration: None,
+        })
+    }
+
+    /// A sliding window over the most recent files recorded by the camera.
+    pub fn build_live(&self, camera_id: &str) -> Result<Playlist, DbError> {
         let files = self
             .db
-            .query_files(Some(time_range.start), Some(time_range.end));
+            .query_latest_timed_files(camera_id, self.live_window)?;
+
+        // Files and discontinuities that slid out of the window still count.
+        let (media_sequence, discontinuity_sequence) = match files.first() {
+            Some(first) => (
+                self.db.media_sequence(camera_id, first)?,
+                self.db.discontinuity_sequence(camera_id, first)?,
+            ),
+            None => (1, 0),
+        };
+
+        Ok(Playlist {
+            kind: PlaylistKind::LIVE,
+            media_sequence,
+            discontinuity_sequence,
+            gaps: self.gaps_between(camera_id, &files)?,
+            files,
+            max_duration: self.max_chunk_seconds,
+        })
+    }
 
-        Playlist {
+    fn gaps_between(&self, camera_id: &str, files: &[TimedFile]) -> Result<Vec<Gap>, DbError> {
+        match (files.first(), files.last()) {
+            (Some(first), Some(last)) => {
+                self.db
+                    .query_gaps(camera_id, first.start_time, last.start_time)
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Where footage of the camera exists within `time_range`, and where it's missing.
+    pub fn build_timeline(
+        &self,
+        camera_id: &str,
+        time_range: OnDemandTimeRange,
+    ) -> Result<Timeline, DbError> {
+        let files =
+            self.db
+                .query_timed_files(camera_id, Some(time_range.start), Some(time_range.end))?;
+        let gaps = self
+            .db
+            .query_overlapping_gaps(camera_id, time_range.start, time_range.end)?;
+
+        Ok(Timeline::new(camera_id, time_range, &files, gaps))
+    }
+
+    /// Files to export a clip of `time_range` from.
+    pub fn clip_files(
+        &self,
+        camera_id: &str,
+        time_range: OnDemandTimeRange,
+    ) -> Result<Vec<TimedFile>, DbError> {
+        self.db
+            .query_timed_files(camera_id, Some(time_range.start), Some(time_range.end))
+    }
+
+    /// DASH equivalent of [`PlaylistBuilder::build_on_demand`].
+    pub fn build_on_demand_manifest(
+        &self,
+        camera_id: &str,
+        time_range: OnDemandTimeRange,
+    ) -> Result<Manifest, DbError> {
+        let files =
+            self.db
+                .query_timed_files(camera_id, Some(time_range.start), Some(time_range.end))?;
+
+        Ok(Manifest {
             kind: PlaylistKind::VOD,
             files,
+        })
+    }
+
+    /// DASH equivalent of [`PlaylistBuilder::build_live`].
+    pub fn build_live_manifest(&self, camera_id: &str) -> Result<Manifest, DbError> {
+        let files = self
+            .db
+            .query_latest_timed_files(camera_id, self.live_window)?;
+
+        Ok(Manifest {
+            kind: PlaylistKind::LIVE,
+            files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ffmpeg_next::Rational;
+
+    use std::str::FromStr;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use crate::db::Database;
+    use crate::execution::{should_roll, ChunkSpan, IndexWrite, PendingIndex, PlaylistBuilder};
+    use crate::playlist::PlaylistFile;
+    use crate::test_util::scratch_dir;
+
+    const MPEG_TS_TIME_BASE: Rational = Rational(1, 90_000);
+
+    #[test]
+    pub fn test_span_duration_includes_last_frame() {
+        let mut span = ChunkSpan::default();
+        assert!(span.is_empty());
+
+        // 3 frames at 30fps, each carrying its own duration.
+        for frame in 0..3 {
+            span.push(90_000 + frame * 3_000, 3_000, MPEG_TS_TIME_BASE);
         }
+
+        assert!(!span.is_empty());
+        assert_eq!(span.duration_seconds(), 0.1);
+    }
+
+    #[test]
+    pub fn test_span_duration_without_packet_durations() {
+        let mut span = ChunkSpan::default();
+
+        // RTSP sources often leave the packet duration unset, fall back to the frame interval.
+        for frame in 0..450 {
+            span.push(frame * 6_000, 0, MPEG_TS_TIME_BASE);
+        }
+
+        assert_eq!(span.duration_seconds(), 30.0);
+    }
+
+    #[test]
+    pub fn test_span_duration_with_reordered_frames() {
+        let mut span = ChunkSpan::default();
+
+        // I P B B, in decode order.
+        for pts in [0, 9_000, 3_000, 6_000] {
+            span.push(pts, 3_000, MPEG_TS_TIME_BASE);
+        }
+
+        assert_eq!(span.duration_seconds(), 12_000.0 / 90_000.0);
+    }
+
+    #[test]
+    pub fn test_should_roll_waits_for_keyframe() {
+        // 10s roll, keyframe every 4s, up to 10s of overshoot.
+        assert!(!should_roll(0, 8 * 90_000, MPEG_TS_TIME_BASE, true, 10, 10));
+        assert!(!should_roll(
+            0,
+            11 * 90_000,
+            MPEG_TS_TIME_BASE,
+            false,
+            10,
+            10
+        ));
+        assert!(should_roll(0, 12 * 90_000, MPEG_TS_TIME_BASE, true, 10, 10));
+    }
+
+    #[test]
+    pub fn test_should_roll_after_max_overshoot() {
+        // A camera with a 60s GOP still rolls once the overshoot runs out.
+        assert!(!should_roll(
+            0,
+            19 * 90_000,
+            MPEG_TS_TIME_BASE,
+            false,
+            10,
+            10
+        ));
+        assert!(should_roll(
+            0,
+            20 * 90_000,
+            MPEG_TS_TIME_BASE,
+            false,
+            10,
+            10
+        ));
+    }
+
+    #[test]
+    pub fn test_pending_index() {
+        let scratch = scratch_dir();
+        let path = scratch.path().join("v0.db");
+        let db = Database::file(&path).unwrap();
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let write = |seq_num: i64| IndexWrite::File {
+            start_time: t1 + TimeDelta::seconds(15 * seq_num),
+            file: PlaylistFile {
+                duration: 15.0,
+                id: format!("{seq_num:0>9}.ts"),
+                init: None,
+                media_start: None,
+            },
+        };
+
+        // Make writes to the index fail from another connection.
+        let other = rusqlite::Connection::open(&path).unwrap();
+        other
+            .execute_batch("ALTER TABLE video_files RENAME TO unavailable")
+            .unwrap();
+
+        let mut pending = PendingIndex::default();
+        pending.push(&db, "porch", write(1));
+        pending.push(
+            &db,
+            "porch",
+            IndexWrite::Gap {
+                start_time: t1 + TimeDelta::seconds(15),
+                end_time: t1 + TimeDelta::seconds(30),
+            },
+        );
+        assert_eq!(pending.writes.len(), 2);
+
+        // Once the database recovers, the buffered writes go in first.
+        other
+            .execute_batch("ALTER TABLE unavailable RENAME TO video_files")
+            .unwrap();
+        pending.push(&db, "porch", write(2));
+        assert!(pending.writes.is_empty());
+
+        let ids: Vec<String> = db
+            .query_files("porch", None, None)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.id)
+            .collect();
+        assert_eq!(ids, vec!["000000001.ts", "000000002.ts"]);
+        assert_eq!(
+            db.query_overlapping_gaps("porch", t1, t1 + TimeDelta::hours(1))
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    pub fn test_discontinuity_sequence() {
+        let db = Database::memory();
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let append = |seq_num: i64, start: i64| {
+            db.append_file(
+                "porch",
+                t1 + TimeDelta::seconds(start),
+                PlaylistFile {
+                    duration: 15.0,
+                    id: format!("{seq_num:0>9}.ts"),
+                    init: None,
+                    media_start: None,
+                },
+            )
+            .unwrap();
+        };
+
+        append(1, 0);
+        // The recorder restarted without recording a gap.
+        append(2, 60);
+        append(3, 75);
+        // The camera reconnected.
+        db.append_gap(
+            "porch",
+            t1 + TimeDelta::seconds(90),
+            t1 + TimeDelta::seconds(95),
+        )
+        .unwrap();
+        append(4, 95);
+        append(5, 110);
+
+        let builder = PlaylistBuilder::new(&db).with_live_window(3);
+        let live = builder.build_live("porch").unwrap();
+        assert_eq!(live.discontinuity_sequence, 1);
+        assert_eq!(live.render().matches("#EXT-X-DISCONTINUITY\r\n").count(), 1);
+
+        let live = builder.with_live_window(2).build_live("porch").unwrap();
+        assert_eq!(live.discontinuity_sequence, 2);
+        assert_eq!(live.render().matches("#EXT-X-DISCONTINUITY\r\n").count(), 0);
+    }
+
+    #[test]
+    pub fn test_media_sequence() {
+        let db = Database::memory();
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let append = |seq_num: i64| {
+            db.append_file(
+                "porch",
+                t1 + TimeDelta::seconds(30 * seq_num),
+                PlaylistFile {
+                    duration: 30.0,
+                    id: format!("{seq_num:0>9}.ts"),
+                    init: None,
+                    media_start: None,
+                },
+            )
+            .unwrap();
+        };
+        // The first two chunks were pruned already, and the sixth was empty so never indexed.
+        for seq_num in [3, 4, 5, 7, 8, 9] {
+            append(seq_num);
+        }
+
+        let builder = PlaylistBuilder::new(&db).with_live_window(3);
+        let live = builder.build_live("porch").unwrap();
+        assert_eq!(live.media_sequence, 6);
+        assert_eq!(live.target_duration(), 30);
+
+        // The window slides on by one file, and so does its media sequence number.
+        append(10);
+        let slid = builder.build_live("porch").unwrap();
+        assert_eq!(slid.files[0], live.files[1]);
+        assert_eq!(slid.media_sequence, live.media_sequence + 1);
+
+        // Files that overran the bound, e.g. recorded with an older config, still fit.
+        let bounded = builder.clone().with_max_chunk_seconds(15 + 10);
+        assert_eq!(bounded.build_live("porch").unwrap().target_duration(), 30);
+        let bounded = bounded.with_max_chunk_seconds(40);
+        assert_eq!(bounded.build_live("porch").unwrap().target_duration(), 40);
+
+        let on_demand = builder
+            .build_on_demand(
+                "porch",
+                (t1 + TimeDelta::seconds(120), t1 + TimeDelta::seconds(200)).into(),
+            )
+            .unwrap();
+        assert_eq!(on_demand.files[0].file.id, "000000004.ts");
+        assert_eq!(on_demand.media_sequence, 4);
     }
 }
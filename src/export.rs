@@ -0,0 +1,488 @@
+//! Export of a time range of footage as a single MP4 file.
+//!
+//! The chunks overlapping the range are remuxed, without re-encoding, into one faststart MP4.
+//! The clip starts at the last keyframe at or before the requested start, so it always begins
+//! with a decodable frame, and ends with the last packet before the requested end. The clip is
+//! streamed from a scratch directory rather than read into memory, and clips are limited to
+//! [`MAX_CLIP_DURATION`] since the chunks have to be copied there first.
+
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use ffmpeg_next::format::context::Output;
+use ffmpeg_next::media::Type;
+use ffmpeg_next::{format, Dictionary, Packet, Rational, Rescale};
+use tempfile::TempDir;
+use tokio::io::AsyncReadExt;
+use tracing::{debug, info, warn};
+use warp::hyper::Body;
+
+use crate::chunk::file::add_stream;
+use crate::playlist::TimedFile;
+use crate::upload::{ReadError, Uploader};
+
+/// Longest clip that can be exported at once.
+pub const MAX_CLIP_DURATION: TimeDelta = TimeDelta::hours(1);
+
+/// Size of the pieces a clip is streamed in.
+const STREAM_BUFFER_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("no footage was recorded in the requested range")]
+    NoFootage,
+
+    #[error(
+        "clips must end after they start, and be at most {} minutes long",
+        MAX_CLIP_DURATION.num_minutes()
+    )]
+    InvalidRange,
+
+    #[error(transparent)]
+    Read(#[from] ReadError),
+
+    #[error("failed to remux clip: {0}")]
+    Remux(#[from] ffmpeg_next::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("remuxing clip failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Check that a clip of `start..end` can be exported, before looking up any footage for it.
+pub fn check_range(start: DateTime<Utc>, end: DateTime<Utc>) -> Result<(), ExportError> {
+    if end <= start || end - start > MAX_CLIP_DURATION {
+        return Err(ExportError::InvalidRange);
+    }
+
+    Ok(())
+}
+
+/// An exported MP4 file. The scratch directory it's in is deleted once it has been sent, or the
+/// clip is dropped.
+pub struct ExportedClip {
+    directory: TempDir,
+    file: tokio::fs::File,
+    pub len: u64,
+}
+
+impl ExportedClip {
+    /// Stream the clip as a response body.
+    pub fn into_body(self) -> Body {
+        let (mut sender, body) = Body::channel();
+
+        let ExportedClip {
+            directory,
+            mut file,
+            ..
+        } = self;
+        tokio::spawn(async move {
+            let mut buffer = vec![0; STREAM_BUFFER_BYTES];
+            loop {
+                match file.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        // The client went away.
+                        if sender
+                            .send_data(Bytes::copy_from_slice(&buffer[..n]))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "failed to read exported clip");
+                        sender.abort();
+                        break;
+                    }
+                }
+            }
+
+            drop(file);
+            drop(directory);
+        });
+
+        body
+    }
+}
+
+/// A chunk to include in the clip, already on local disk.
+#[derive(Debug, Clone)]
+pub struct ClipInput {
+    pub path: PathBuf,
+    pub start_time: DateTime<Utc>,
+}
+
+/// Export the footage in `files` between `start` and `end` as an MP4 file.
+pub async fn export<U: Uploader>(
+    uploader: &U,
+    files: &[TimedFile],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<ExportedClip, ExportError> {
+    check_range(start, end)?;
+    if files.is_empty() {
+        return Err(ExportError::NoFootage);
+    }
+
+    // Holds the chunks and the clip while exporting, deleted when dropped.
+    let directory = tempfile::Builder::new()
+        .prefix("camerars-export-")
+        .tempdir()?;
+
+    let inputs = fetch_inputs(uploader, files, directory.path()).await?;
+
+    let output = directory.path().join("clip.mp4");
+    let clip = output.clone();
+    tokio::task::spawn_blocking(move || remux_clip(&inputs, start, end, &clip)).await??;
+
+    let file = tokio::fs::File::open(&output).await?;
+    let len = file.metadata().await?.len();
+
+    Ok(ExportedClip {
+        directory,
+        file,
+        len,
+    })
+}
+
+/// Read the chunks from storage into `directory`. Fragmented MP4 chunks get their init segment
+/// put in front of them, so that every input can be opened on its own.
+async fn fetch_inputs<U: Uploader>(
+    uploader: &U,
+    files: &[TimedFile],
+    directory: &Path,
+) -> Result<Vec<ClipInput>, ExportError> {
+    let mut inputs = Vec::with_capacity(files.len());
+
+    for (i, timed) in files.iter().enumerate() {
+        let mut data = match &timed.file.init {
+            Some(init) => uploader.read_chunk(init).await?,
+            None => Vec::new(),
+        };
+        data.extend(uploader.read_chunk(&timed.file.id).await?);
+
+        let extension = if timed.file.init.is_some() {
+            "mp4"
+        } else {
+            "ts"
+        };
+        let path = directory.join(format!("{i:0>9}.{extension}"));
+        tokio::fs::write(&path, data).await?;
+
+        inputs.push(ClipInput {
+            path,
+            start_time: timed.start_time,
+        });
+    }
+
+    Ok(inputs)
+}
+
+/// Remux `inputs` into a single MP4 at `output`, trimmed to `start..end`.
+pub fn remux_clip(
+    inputs: &[ClipInput],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    output: &Path,
+) -> Result<(), ExportError> {
+    let mut clip: Option<Clip> = None;
+    // Packets since the last keyframe before `start`, written once `start` is reached.
+    let mut pending: Vec<Packet> = Vec::new();
+    let mut started = false;
+
+    'inputs: for input in inputs {
+        debug!(path = ?input.path, "adding chunk to clip");
+        let mut input_context = format::input(&input.path)?;
+
+        let Some(video_index) = input_context.streams().best(Type::Video).map(|s| s.index()) else {
+            continue;
+        };
+        let audio_index = input_context
+            .streams()
+            .best(Type::Audio)
+            .map(|stream| stream.index());
+        let time_bases: Vec<Rational> = input_context
+            .streams()
+            .map(|stream| stream.time_base())
+            .collect();
+
+        let clip = match &mut clip {
+            Some(clip) => clip,
+            None => clip.insert(Clip::create(
+                output,
+                &input_context,
+                video_index,
+                audio_index,
+            )?),
+        };
+        clip.begin_chunk();
+
+        let mut first_video_pts = None;
+        loop {
+            let mut packet = Packet::empty();
+            match packet.read(&mut input_context) {
+                Ok(()) => {}
+                Err(ffmpeg_next::Error::Eof) => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            let index = packet.stream();
+            let target = if index == video_index {
+                0
+            } else if Some(index) == audio_index && clip.has_audio {
+                1
+            } else {
+                continue;
+            };
+            packet.rescale_ts(time_bases[index], clip.time_bases[target]);
+            packet.set_stream(target);
+
+            if target == 0 {
+                let Some(pts) = packet.pts() else {
+                    continue;
+                };
+                let first_pts = *first_video_pts.get_or_insert(pts);
+                let offset = f64::from(clip.time_bases[0]) * (pts - first_pts) as f64;
+                let time = input.start_time
+                    + chrono::TimeDelta::milliseconds((offset * 1000.0).round() as i64);
+
+                if time > end {
+                    break 'inputs;
+                }
+                if !started {
+                    if packet.is_key() {
+                        pending.clear();
+                    } else if pending.is_empty() {
+                        // Nothing to decode this frame from.
+                        continue;
+                    }
+
+                    if time < start {
+                        pending.push(packet);
+                        continue;
+                    }
+
+                    started = true;
+                    for packet in pending.drain(..) {
+                        clip.write(packet)?;
+                    }
+                }
+            } else if !started {
+                if !pending.is_empty() {
+                    pending.push(packet);
+                }
+                continue;
+            }
+
+            clip.write(packet)?;
+        }
+    }
+
+    let Some(mut clip) = clip.filter(|_| started) else {
+        return Err(ExportError::NoFootage);
+    };
+
+    clip.output.write_trailer()?;
+    info!(path = ?output, "exported clip");
+
+    Ok(())
+}
+
+/// The MP4 being written, with timestamps made continuous across chunks.
+struct Clip {
+    output: Output,
+    has_audio: bool,
+    time_bases: Vec<Rational>,
+    retimer: Retimer,
+    /// Packets of the current chunk that arrived before its first video packet, which decides
+    /// how they're retimed.
+    waiting: Vec<Packet>,
+}
+
+impl Clip {
+    fn create(
+        path: &Path,
+        input: &format::context::Input,
+        video_index: usize,
+        audio_index: Option<usize>,
+    ) -> Result<Self, ExportError> {
+        let mut output = format::output_as(&path, "mp4")?;
+
+        add_stream(
+            &mut output,
+            input.stream(video_index).unwrap().parameters(),
+            0,
+        );
+        if let Some(audio_index) = audio_index {
+            add_stream(
+                &mut output,
+                input.stream(audio_index).unwrap().parameters(),
+                0,
+            );
+        }
+
+        // Move the index to the front, so the clip starts playing before it's fully downloaded.
+        let mut options = Dictionary::new();
+        options.set("movflags", "faststart");
+        output.write_header_with(options)?;
+
+        let time_bases: Vec<Rational> = output.streams().map(|s| s.time_base()).collect();
+
+        Ok(Self {
+            output,
+            has_audio: audio_index.is_some(),
+            retimer: Retimer::new(time_bases.clone()),
+            time_bases,
+            waiting: Vec::new(),
+        })
+    }
+
+    fn begin_chunk(&mut self) {
+        self.retimer.begin_chunk();
+        self.waiting.clear();
+    }
+
+    fn write(&mut self, packet: Packet) -> Result<(), ExportError> {
+        let Some(dts) = packet.dts().or(packet.pts()) else {
+            return Ok(());
+        };
+        if self.retimer.is_started() {
+            return self.write_retimed(packet, dts);
+        }
+        if packet.stream() != 0 {
+            self.waiting.push(packet);
+            return Ok(());
+        }
+
+        self.retimer.start(dts);
+        self.write_retimed(packet, dts)?;
+        for packet in std::mem::take(&mut self.waiting) {
+            if let Some(dts) = packet.dts().or(packet.pts()) {
+                self.write_retimed(packet, dts)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_retimed(&mut self, mut packet: Packet, dts: i64) -> Result<(), ExportError> {
+        let Some(shift) = self.retimer.shift(packet.stream(), dts, packet.duration()) else {
+            debug!(
+                stream = packet.stream(),
+                "dropping packet that overlaps the previous chunk"
+            );
+            return Ok(());
+        };
+
+        packet.set_dts(Some(dts - shift));
+        packet.set_pts(packet.pts().map(|pts| pts - shift));
+        packet.set_position(-1);
+        packet.write_interleaved(&mut self.output)?;
+
+        Ok(())
+    }
+}
+
+/// Makes the timestamps of a clip continuous across chunks. Each chunk's video is placed right
+/// after the video of the previous one, and its other streams are shifted by the same amount,
+/// so that they stay in sync with the video.
+#[derive(Debug)]
+struct Retimer {
+    time_bases: Vec<Rational>,
+    /// Timestamp right after the last video packet written.
+    video_end: i64,
+    /// Last timestamp written to each stream.
+    last_dts: Vec<Option<i64>>,
+    /// Amount subtracted from the video timestamps of the current chunk, once it has started.
+    shift: Option<i64>,
+}
+
+impl Retimer {
+    fn new(time_bases: Vec<Rational>) -> Self {
+        Self {
+            last_dts: vec![None; time_bases.len()],
+            time_bases,
+            video_end: 0,
+            shift: None,
+        }
+    }
+
+    fn begin_chunk(&mut self) {
+        self.shift = None;
+    }
+
+    fn is_started(&self) -> bool {
+        self.shift.is_some()
+    }
+
+    /// Start the current chunk at its first video packet.
+    fn start(&mut self, video_dts: i64) {
+        self.shift = Some(video_dts - self.video_end);
+    }
+
+    /// Amount to subtract from the timestamps of a packet of `stream`, or `None` if the chunk
+    /// hasn't started yet or the packet would go back before what was already written to the
+    /// stream, e.g. audio that overlaps the previous chunk.
+    fn shift(&mut self, stream: usize, dts: i64, duration: i64) -> Option<i64> {
+        let shift = self
+            .shift?
+            .rescale(self.time_bases[0], self.time_bases[stream]);
+        let shifted = dts - shift;
+        if self.last_dts[stream].is_some_and(|last| shifted <= last) {
+            return None;
+        }
+
+        self.last_dts[stream] = Some(shifted);
+        if stream == 0 {
+            self.video_end = self.video_end.max(shifted + duration.max(1));
+        }
+
+        Some(shift)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ffmpeg_next::Rational;
+
+    use crate::export::Retimer;
+
+    #[test]
+    pub fn test_retimer() {
+        let video = Rational(1, 90_000);
+        let audio = Rational(1, 48_000);
+        let mut retimer = Retimer::new(vec![video, audio]);
+        let retime = |retimer: &mut Retimer, stream: usize, dts: i64| {
+            let duration = if stream == 0 { 3_000 } else { 1_024 };
+            retimer
+                .shift(stream, dts, duration)
+                .map(|shift| dts - shift)
+        };
+
+        // Audio starts half a second after the video, and keeps that offset in the clip.
+        retimer.begin_chunk();
+        assert_eq!(retime(&mut retimer, 0, 900_000), None);
+        retimer.start(900_000);
+        assert_eq!(retime(&mut retimer, 0, 900_000), Some(0));
+        assert_eq!(retime(&mut retimer, 1, 480_000 + 24_000), Some(24_000));
+        assert_eq!(retime(&mut retimer, 0, 903_000), Some(3_000));
+        assert_eq!(retime(&mut retimer, 1, 480_000 + 25_024), Some(25_024));
+
+        // The next chunk starts on a timeline of its own, with audio 0.6s behind.
+        retimer.begin_chunk();
+        retimer.start(9_000_000);
+        assert_eq!(retime(&mut retimer, 0, 9_000_000), Some(6_000));
+        // 6000 ticks of video are 3200 of audio.
+        assert_eq!(
+            retime(&mut retimer, 1, 4_800_000 + 28_800),
+            Some(3_200 + 28_800)
+        );
+        // Audio overlapping what was already written is dropped.
+        assert_eq!(retime(&mut retimer, 1, 4_800_000 + 12_000), None);
+    }
+}
@@ -1,10 +1,24 @@
+pub mod backoff;
+pub mod camera;
 pub mod chunk;
+pub mod clock;
+pub mod config;
+pub mod dash;
 pub mod execution;
+pub mod export;
+pub mod metrics;
 pub mod upload;
 
 pub mod playlist;
+pub mod reindex;
 
 pub mod db;
 pub mod reply;
+pub mod retention;
 pub mod server;
+pub mod shutdown;
 pub mod static_assets;
+pub mod timeline;
+
+#[cfg(test)]
+mod test_util;
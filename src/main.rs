@@ -1,23 +1,55 @@
 extern crate ffmpeg_next as ffmpeg;
 
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use camerars::chunk::ChunkWriterFactory;
 use clap::Parser;
 use dotenvy::dotenv_override;
 use tracing::info;
 
+use camerars::camera::Camera;
 use camerars::chunk::file::FileChunkWriterFactory;
+use camerars::chunk::fmp4::FragmentedMp4ChunkWriterFactory;
+use camerars::chunk::ChunkFormat;
+use camerars::config::{Config, Settings};
 use camerars::db::Database;
-use camerars::execution::{Pipeline, PlaylistBuilder};
+use camerars::execution::{Pipeline, PlaylistBuilder, DEFAULT_MAX_ROLL_OVERSHOOT_SECONDS};
+use camerars::metrics::Metrics;
+use camerars::reindex::{reindex, ReindexReport};
+use camerars::retention::Pruner;
 use camerars::server::backend;
-use camerars::upload::s3;
+use camerars::shutdown::Shutdown;
+use camerars::upload::queue::UploadQueue;
+use camerars::upload::tiered::TieredUploader;
+use camerars::upload::CameraUploaders;
 
+/// Record cameras into chunks, upload them to storage and serve them for playback.
 #[derive(Parser)]
 pub struct Cli {
-    pub source: String,
-    #[clap(long)]
-    pub prefix: Option<String>,
+    /// TOML config file, see `camerars.example.toml`. Options given here take precedence.
+    #[clap(long, env = "CAMERARS_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[clap(flatten)]
+    pub settings: Settings,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Rebuild the index of recordings from the chunks on local disk and in storage, e.g. after
+    /// the database was lost. Replaces whatever was indexed for the cameras before.
+    Reindex {
+        /// Only reindex this camera.
+        #[clap(long)]
+        camera: Option<String>,
+        /// Probe the chunks and report what would be indexed, without changing the database.
+        #[clap(long)]
+        dry_run: bool,
+    },
 }
 
 pub fn main() {
@@ -27,10 +59,26 @@ pub fn main() {
     ffmpeg::init().expect("ffmpeg initialization should succeed");
 
     let cli = Cli::parse();
+    let config = match Config::load(cli.config.as_deref(), cli.settings) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            std::process::exit(2);
+        }
+    };
+    let cameras = &config.cameras;
 
-    let prefix = cli.prefix.unwrap_or_else(|| "/".to_string());
+    let database = match Database::file(&config.database) {
+        Ok(database) => database,
+        Err(e) => {
+            eprintln!("error: failed to open {}: {e}", config.database.display());
+            std::process::exit(2);
+        }
+    };
 
-    let database = Database::file("v0.db");
+    if let Some(Command::Reindex { camera, dry_run }) = cli.command {
+        std::process::exit(run_reindex(&config, &database, camera.as_deref(), dry_run));
+    }
 
     // Create a new runtime just for serving file requests from disk.
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -38,25 +86,195 @@ pub fn main() {
         .build()
         .unwrap();
 
-    let uploader = s3::new_s3_uploader(prefix.as_ref());
-    let uploader = Arc::new(uploader);
-    {
-        let uploader = Arc::clone(&uploader);
+    // Stop recording, serving and uploading on SIGTERM or SIGINT, or exit on a second one.
+    let shutdown = Shutdown::new();
+    runtime.spawn(shutdown.clone().listen_for_signals());
+
+    let metrics = Metrics::new();
+
+    let uploader = match config.storage.uploader(&config.prefix) {
+        Ok(uploader) => uploader,
+        Err(e) => {
+            eprintln!("error: failed to open storage: {e:#}");
+            std::process::exit(2);
+        }
+    };
+    let uploaders: CameraUploaders<_> = cameras
+        .iter()
+        .map(|camera| {
+            let uploader = uploader.with_prefix(camera.prefix(&config.prefix));
+            let uploader = TieredUploader::new(camera.directory(&config.recordings), uploader);
+            (camera.id.clone(), Arc::new(uploader))
+        })
+        .collect();
+    let server = {
+        let uploaders = uploaders.clone();
         let database = database.clone();
+        let shutdown = shutdown.clone();
+        let metrics = metrics.clone();
+        let bind = config.bind;
+        let max_chunk_seconds = config.roll_seconds + DEFAULT_MAX_ROLL_OVERSHOOT_SECONDS;
 
         runtime.spawn(async move {
-            let playlist_builder = PlaylistBuilder::new(&database);
-            let service = backend(playlist_builder, uploader);
-            info!("Server is running @ 127.0.0.1:3030");
+            let playlist_builder =
+                PlaylistBuilder::new(&database).with_max_chunk_seconds(max_chunk_seconds);
+            let service = backend(playlist_builder, uploaders, metrics);
+            let (address, server) = warp::serve(service)
+                .bind_with_graceful_shutdown(bind, async move { shutdown.requested().await });
+            info!("Server is running @ {address}");
+
+            server.await
+        })
+    };
+
+    // Pick up any chunks that weren't uploaded before the last shutdown, then start uploading.
+    let uploads = UploadQueue::new(&database);
+    for camera in cameras.iter() {
+        uploads.recover(&camera.id, camera.directory(&config.recordings));
+    }
+    runtime.spawn(uploads.clone().run(uploaders.clone()));
+
+    let pruner = Pruner::new(&database, config.retention).with_dry_run(config.prune_dry_run);
+    runtime.spawn(pruner.run(uploaders));
+
+    // Each camera records on its own thread, into its own directory.
+    let pipelines: Vec<_> = cameras
+        .iter()
+        .map(|camera| {
+            let directory = camera.directory(&config.recordings);
+            let quota = config.local_quota_bytes;
+
+            match config.format {
+                ChunkFormat::Ts => {
+                    let mut chunk_writer = FileChunkWriterFactory::new(directory);
+                    if let Some(max_bytes) = quota {
+                        chunk_writer = chunk_writer.with_quota(&camera.id, &database, max_bytes);
+                    }
+                    spawn_pipeline(
+                        camera,
+                        chunk_writer,
+                        config.roll_seconds,
+                        &uploads,
+                        &database,
+                        &shutdown,
+                        &metrics,
+                    )
+                }
+                ChunkFormat::Fmp4 => {
+                    let mut chunk_writer = FragmentedMp4ChunkWriterFactory::new(directory);
+                    if let Some(max_bytes) = quota {
+                        chunk_writer = chunk_writer.with_quota(&camera.id, &database, max_bytes);
+                    }
+                    spawn_pipeline(
+                        camera,
+                        chunk_writer,
+                        config.roll_seconds,
+                        &uploads,
+                        &database,
+                        &shutdown,
+                        &metrics,
+                    )
+                }
+            }
+        })
+        .collect();
+
+    for pipeline in pipelines {
+        pipeline.join().expect("pipeline thread panicked");
+    }
+
+    // The pipelines queued their last chunks on the way out, give them a chance to upload.
+    runtime.block_on(async {
+        if uploads.drain(config.upload_drain_timeout).await {
+            info!("all uploads finished");
+        }
+        server.await.expect("server task panicked");
+    });
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    info!("shut down");
+}
+
+/// Reindex the configured cameras, or just `only`, returning the exit code.
+fn run_reindex(config: &Config, database: &Database, only: Option<&str>, dry_run: bool) -> i32 {
+    if let Some(camera_id) = only {
+        if config.cameras.get(camera_id).is_none() {
+            eprintln!("error: no camera {camera_id:?} is configured");
+            return 2;
+        }
+    }
+
+    let uploader = match config.storage.uploader(&config.prefix) {
+        Ok(uploader) => uploader,
+        Err(e) => {
+            eprintln!("error: failed to open storage: {e:#}");
+            return 2;
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
 
-            warp::serve(service).run(([127, 0, 0, 1], 3030)).await
-        });
+    let mut code = 0;
+    for camera in config.cameras.iter() {
+        if only.is_some_and(|camera_id| camera_id != camera.id) {
+            continue;
+        }
+
+        let uploader = TieredUploader::new(
+            camera.directory(&config.recordings),
+            uploader.with_prefix(camera.prefix(&config.prefix)),
+        );
+        match runtime.block_on(reindex(database, &camera.id, &uploader, dry_run)) {
+            Ok(report) => {
+                print_report(&camera.id, &report);
+                if !report.failed.is_empty() {
+                    code = 1;
+                }
+            }
+            Err(e) => {
+                eprintln!("error: failed to reindex {}: {e:#}", camera.id);
+                code = 1;
+            }
+        }
     }
 
-    let mut chunk_writer = FileChunkWriterFactory::new("recordings");
+    code
+}
+
+/// One line per camera, and one per chunk that couldn't be indexed.
+fn print_report(camera_id: &str, report: &ReindexReport) {
+    println!(
+        "{camera_id}: indexed {} chunks with {} gaps, {} failed",
+        report.files.len(),
+        report.gaps.len(),
+        report.failed.len()
+    );
+    for failed in &report.failed {
+        println!("{camera_id}: {}: {}", failed.name, failed.error);
+    }
+}
+
+fn spawn_pipeline<F: ChunkWriterFactory + Send + 'static>(
+    camera: &Camera,
+    mut chunk_writer: F,
+    roll_seconds: u32,
+    uploads: &UploadQueue,
+    database: &Database,
+    shutdown: &Shutdown,
+    metrics: &Metrics,
+) -> JoinHandle<()> {
     chunk_writer.init();
 
-    Pipeline::from(cli.source.as_str(), runtime.handle().clone())
-        .with_roll_seconds(15)
-        .run(&mut chunk_writer, Arc::clone(&uploader), &database);
+    let uploads = uploads.clone();
+    let database = database.clone();
+    let mut pipeline = Pipeline::for_camera(camera)
+        .with_roll_seconds(roll_seconds)
+        .with_shutdown(shutdown)
+        .with_metrics(metrics);
+
+    std::thread::Builder::new()
+        .name(format!("pipeline-{}", camera.id))
+        .spawn(move || pipeline.run(&mut chunk_writer, &uploads, &database))
+        .expect("spawning pipeline thread should succeed")
 }
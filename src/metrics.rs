@@ -0,0 +1,92 @@
+//! Metrics, served at `/metrics` in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use chrono::TimeDelta;
+
+use crate::clock::ClockSource;
+
+#[derive(Clone, Default)]
+pub struct Metrics {
+    clocks: Arc<Mutex<BTreeMap<String, ClockMetrics>>>,
+}
+
+/// State of a camera's clock as of its last chunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockMetrics {
+    pub source: ClockSource,
+    /// Wall-clock time of the last frame of the chunk, minus the system time it was closed at.
+    pub drift_seconds: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record how far the clock of `camera_id` had drifted from the system clock when its last
+    /// chunk was closed.
+    pub fn record_clock_drift(&self, camera_id: &str, source: ClockSource, drift: TimeDelta) {
+        let clock = ClockMetrics {
+            source,
+            drift_seconds: drift.num_microseconds().unwrap_or(i64::MAX) as f64 / 1e6,
+        };
+
+        self.clocks
+            .lock()
+            .unwrap()
+            .insert(camera_id.to_string(), clock);
+    }
+
+    pub fn clock(&self, camera_id: &str) -> Option<ClockMetrics> {
+        self.clocks.lock().unwrap().get(camera_id).copied()
+    }
+
+    /// All metrics, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        writeln!(
+            out,
+            "# HELP camerars_clock_drift_seconds Wall-clock time of the last recorded frame minus the system time its chunk was closed at."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE camerars_clock_drift_seconds gauge").unwrap();
+        for (camera_id, clock) in self.clocks.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "camerars_clock_drift_seconds{{camera=\"{camera_id}\",source=\"{}\"}} {}",
+                clock.source.as_str(),
+                clock.drift_seconds
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeDelta;
+
+    use crate::clock::ClockSource;
+    use crate::metrics::Metrics;
+
+    #[test]
+    pub fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_clock_drift("porch", ClockSource::Rtcp, TimeDelta::milliseconds(-250));
+        metrics.record_clock_drift("garage", ClockSource::System, TimeDelta::seconds(2));
+
+        assert_eq!(
+            metrics.render(),
+            "# HELP camerars_clock_drift_seconds Wall-clock time of the last recorded frame minus the system time its chunk was closed at.\n\
+             # TYPE camerars_clock_drift_seconds gauge\n\
+             camerars_clock_drift_seconds{camera=\"garage\",source=\"system\"} 2\n\
+             camerars_clock_drift_seconds{camera=\"porch\",source=\"rtcp\"} -0.25\n"
+        );
+    }
+}
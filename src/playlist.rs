@@ -1,6 +1,11 @@
 // Access the database internally here.
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Serialize;
+
+/// Files starting less than this long after the previous one ended still count as contiguous,
+/// recorded start times jitter a little.
+pub(crate) const MAX_DRIFT: TimeDelta = TimeDelta::milliseconds(500);
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum PlaylistKind {
@@ -11,7 +16,64 @@ pub enum PlaylistKind {
 #[derive(Debug, PartialEq, Clone)]
 pub struct Playlist {
     pub kind: PlaylistKind,
-    pub files: Vec<PlaylistFile>,
+    /// Sequence number of the first file, advances as a live playlist's window slides forward.
+    pub media_sequence: u64,
+    /// Number of discontinuities before the first file, for live playlists.
+    pub discontinuity_sequence: u64,
+    pub files: Vec<TimedFile>,
+    /// Periods during which the camera was disconnected, around the files.
+    pub gaps: Vec<Gap>,
+    /// Longest a chunk of the camera can be, in seconds. Live playlists use it as their target
+    /// duration, which mustn't change as the window slides.
+    pub max_duration: Option<u64>,
+}
+
+impl Playlist {
+    /// Longest segment duration rounded up to whole seconds, which every `EXTINF` must fit in.
+    ///
+    /// With a [`Playlist::max_duration`], that's used instead unless a segment overran it. An
+    /// `EXTINF` only has to fit once rounded to the nearest second, so one that overran by a frame
+    /// doesn't change the target duration.
+    pub fn target_duration(&self) -> u64 {
+        let longest = |round: fn(f64) -> f64| {
+            self.files
+                .iter()
+                .map(|timed| round(timed.file.duration) as u64)
+                .max()
+                .unwrap_or_default()
+        };
+        let target = match self.max_duration {
+            Some(max_duration) => longest(f64::round).max(max_duration),
+            None => longest(f64::ceil),
+        };
+
+        target.max(1)
+    }
+
+    /// See [`is_discontinuity`].
+    pub fn is_discontinuity(&self, previous: &TimedFile, next: &TimedFile) -> bool {
+        is_discontinuity(previous, next, &self.gaps)
+    }
+}
+
+/// Whether playback jumps between `previous` and `next`: they aren't contiguous in time, the
+/// camera was reconnected in between, or the encoding changed. `gaps` must include at least those
+/// that started between the two files.
+pub fn is_discontinuity(previous: &TimedFile, next: &TimedFile, gaps: &[Gap]) -> bool {
+    let expected_start = previous.end_time();
+    let contiguous = (next.start_time - expected_start).abs() <= MAX_DRIFT;
+    let reconnected = gaps
+        .iter()
+        .any(|gap| gap.start_time >= previous.start_time && gap.start_time <= next.start_time);
+
+    !contiguous || reconnected || previous.file.init != next.file.init
+}
+
+/// A period during which nothing was recorded, because the input was disconnected.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Gap {
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -29,8 +91,26 @@ impl From<(DateTime<Utc>, DateTime<Utc>)> for OnDemandTimeRange {
     }
 }
 
+/// A file along with the time it started recording at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedFile {
+    pub start_time: DateTime<Utc>,
+    pub file: PlaylistFile,
+}
+
+impl TimedFile {
+    pub fn end_time(&self) -> DateTime<Utc> {
+        self.start_time + TimeDelta::milliseconds((self.file.duration * 1000.0).round() as i64)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PlaylistFile {
     pub duration: f64,
     pub id: String,
+    /// Init segment the file depends on, for fragmented MP4 files.
+    pub init: Option<String>,
+    /// Presentation time of the first frame in seconds, on the stream's own timeline rather than
+    /// the wall clock. Unknown for files indexed before it was recorded.
+    pub media_start: Option<f64>,
 }
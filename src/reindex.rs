@@ -0,0 +1,341 @@
+//! Rebuilding the index from the chunks in storage, for when the database is lost or corrupted.
+//!
+//! Every chunk on local disk or in the remote store is probed for the presentation time of its
+//! first frame and its duration. Chunks whose timestamps carry on from one another were recorded
+//! over the same connection, and form a session. Chunks don't record the wall-clock time they
+//! were made, but none of them can have been written or uploaded before it was finished, so each
+//! one's modification time bounds when its session started. The tightest bound is used, which is
+//! exact to within milliseconds for a session with a chunk still on local disk. Gaps are recorded
+//! between sessions.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use ffmpeg_next::format;
+use ffmpeg_next::media::Type;
+use tracing::{debug, info, warn};
+
+use crate::chunk::{is_chunk, is_init_segment, seq_num};
+use crate::db::Database;
+use crate::execution::ChunkSpan;
+use crate::playlist::{Gap, PlaylistFile, TimedFile, MAX_DRIFT};
+use crate::upload::tiered::TieredUploader;
+use crate::upload::Uploader;
+
+/// How far the first frame of a chunk may be from the end of the previous one for both to
+/// belong to the same session.
+const SESSION_TOLERANCE_SECONDS: f64 = 1.0;
+
+/// A chunk that was probed successfully.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbedChunk {
+    pub file: PlaylistFile,
+    /// Presentation time of the first frame, in seconds on the camera's clock.
+    pub start_pts: f64,
+    /// When the chunk was last modified on local disk or in the remote store, whichever is
+    /// earlier.
+    pub modified: DateTime<Utc>,
+}
+
+/// A chunk that couldn't be indexed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedChunk {
+    pub name: String,
+    pub error: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ReindexReport {
+    pub files: Vec<TimedFile>,
+    pub gaps: Vec<Gap>,
+    pub failed: Vec<FailedChunk>,
+}
+
+/// Rebuild the index of a camera from the chunks `uploader` holds, replacing whatever was indexed
+/// for it before. Chunks found in the remote store are marked as uploaded. With `dry_run`, the
+/// database is left alone.
+pub async fn reindex<U: Uploader>(
+    database: &Database,
+    camera_id: &str,
+    uploader: &TieredUploader<U>,
+    dry_run: bool,
+) -> anyhow::Result<ReindexReport> {
+    let local = uploader.list_local_chunks().await?;
+    let local_init_segments = uploader.list_local_init_segments().await?;
+    let remote = uploader.remote().list_chunks().await?;
+
+    let mut chunks = HashMap::new();
+    let mut init_segments = HashMap::new();
+    for stored in local.iter().chain(&local_init_segments).chain(&remote) {
+        let path = Path::new(&stored.name);
+        let found = if is_chunk(path) {
+            &mut chunks
+        } else if is_init_segment(path) {
+            &mut init_segments
+        } else {
+            continue;
+        };
+        found
+            .entry(stored.name.clone())
+            .and_modify(|modified: &mut DateTime<Utc>| {
+                *modified = (*modified).min(stored.last_modified)
+            })
+            .or_insert(stored.last_modified);
+    }
+    info!(
+        camera = camera_id,
+        chunks = chunks.len(),
+        init_segments = init_segments.len(),
+        "probing chunks"
+    );
+
+    let scratch = tempfile::Builder::new()
+        .prefix("camerars-reindex-")
+        .tempdir()?;
+
+    let mut probed = Vec::with_capacity(chunks.len());
+    let mut failed = Vec::new();
+    for (name, modified) in chunks {
+        match probe_stored(uploader, &name, modified, &init_segments, scratch.path()).await {
+            Ok(chunk) => probed.push(chunk),
+            Err(e) => {
+                warn!(error = %e, name = name, "failed to probe chunk");
+                failed.push(FailedChunk {
+                    name,
+                    error: format!("{e:#}"),
+                });
+            }
+        }
+    }
+    drop(scratch);
+    failed.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let (files, gaps) = place(probed);
+    if !dry_run {
+        database.replace_index(camera_id, &files, &gaps)?;
+
+        let now = Utc::now();
+        for stored in &remote {
+            let path = uploader.directory().join(&stored.name);
+            database.enqueue_upload(camera_id, &stored.name, &path, now)?;
+            database.complete_upload(camera_id, &stored.name)?;
+        }
+    }
+
+    Ok(ReindexReport {
+        files,
+        gaps,
+        failed,
+    })
+}
+
+/// Fetch the chunk called `name` into `scratch` and probe it. Fragmented MP4 chunks are tried
+/// with the init segment most likely to belong to them first, the newest one that's no newer
+/// than the chunk, and then with the others.
+async fn probe_stored<U: Uploader>(
+    uploader: &TieredUploader<U>,
+    name: &str,
+    modified: DateTime<Utc>,
+    init_segments: &HashMap<String, DateTime<Utc>>,
+    scratch: &Path,
+) -> anyhow::Result<ProbedChunk> {
+    let data = uploader.read_chunk(name).await?;
+
+    let candidates: Vec<Option<&String>> = if name.ends_with(".m4s") {
+        let mut candidates: Vec<_> = init_segments.iter().collect();
+        candidates.sort_by_key(|(_, &init_modified)| {
+            (init_modified > modified, (init_modified - modified).abs())
+        });
+        candidates.into_iter().map(|(init, _)| Some(init)).collect()
+    } else {
+        vec![None]
+    };
+
+    let mut error = anyhow::anyhow!("no init segment to probe the chunk with");
+    for init in candidates {
+        let mut input = match init {
+            Some(init) => uploader.read_chunk(init).await?,
+            None => Vec::new(),
+        };
+        input.extend_from_slice(&data);
+
+        let extension = if init.is_some() { "mp4" } else { "ts" };
+        let path = scratch.join(format!("probe.{extension}"));
+        tokio::fs::write(&path, input).await?;
+
+        // A chunk malformed enough to panic the prober is reported like any other bad chunk.
+        let probed = match tokio::task::spawn_blocking(move || probe(&path)).await {
+            Ok(probed) => probed.map_err(anyhow::Error::from),
+            Err(e) => Err(anyhow::anyhow!("probing the chunk panicked: {e}")),
+        };
+        match probed {
+            Ok((start_pts, duration)) => {
+                debug!(name = name, init = ?init, start_pts, duration, "probed chunk");
+                return Ok(ProbedChunk {
+                    file: PlaylistFile {
+                        duration,
+                        id: name.to_string(),
+                        init: init.cloned(),
+                        media_start: Some(start_pts),
+                    },
+                    start_pts,
+                    modified,
+                });
+            }
+            Err(e) => error = e,
+        }
+    }
+
+    Err(error)
+}
+
+/// Read through the video packets of the chunk at `path`, returning the presentation time of
+/// its first frame and its duration, both in seconds.
+pub fn probe(path: &Path) -> Result<(f64, f64), ffmpeg_next::Error> {
+    let mut input = format::input(&path)?;
+
+    let video = input
+        .streams()
+        .best(Type::Video)
+        .ok_or(ffmpeg_next::Error::StreamNotFound)?;
+    let (video_index, time_base) = (video.index(), video.time_base());
+
+    let mut span = ChunkSpan::default();
+    for (stream, packet) in input.packets() {
+        if stream.index() != video_index {
+            continue;
+        }
+        let pts = packet.pts().or(packet.dts()).unwrap_or_default();
+        span.push(pts, packet.duration(), time_base);
+    }
+
+    if span.is_empty() {
+        return Err(ffmpeg_next::Error::InvalidData);
+    }
+
+    Ok((span.start_seconds(), span.duration_seconds()))
+}
+
+/// Place probed chunks in time, returning the files to index, oldest first, and the gaps between
+/// the sessions they were recorded in.
+pub fn place(mut chunks: Vec<ProbedChunk>) -> (Vec<TimedFile>, Vec<Gap>) {
+    chunks
+        .sort_by(|a, b| (seq_num(&a.file.id), &a.file.id).cmp(&(seq_num(&b.file.id), &b.file.id)));
+
+    let mut files: Vec<TimedFile> = Vec::with_capacity(chunks.len());
+    let mut gaps = Vec::new();
+    let sessions = chunks.chunk_by(|previous, next| {
+        (next.start_pts - (previous.start_pts + previous.file.duration)).abs()
+            <= SESSION_TOLERANCE_SECONDS
+    });
+    for session in sessions {
+        let first_pts = session[0].start_pts;
+        let mut start = session
+            .iter()
+            .map(|chunk| {
+                chunk.modified - seconds(chunk.start_pts - first_pts + chunk.file.duration)
+            })
+            .min()
+            .expect("sessions are never empty");
+
+        // Sessions can't overlap, however late the earlier one's chunks were uploaded.
+        if let Some(previous_end) = files.last().map(TimedFile::end_time) {
+            start = start.max(previous_end);
+            if start - previous_end > MAX_DRIFT {
+                gaps.push(Gap {
+                    start_time: previous_end,
+                    end_time: start,
+                });
+            }
+        }
+
+        files.extend(session.iter().map(|chunk| TimedFile {
+            start_time: start + seconds(chunk.start_pts - first_pts),
+            file: chunk.file.clone(),
+        }));
+    }
+
+    (files, gaps)
+}
+
+fn seconds(seconds: f64) -> TimeDelta {
+    TimeDelta::milliseconds((seconds * 1000.0).round() as i64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use crate::playlist::{Gap, PlaylistFile};
+    use crate::reindex::{place, ProbedChunk};
+
+    fn chunk(seq_num: u64, start_pts: f64, modified: DateTime<Utc>) -> ProbedChunk {
+        ProbedChunk {
+            file: PlaylistFile {
+                duration: 15.0,
+                id: format!("{seq_num:0>9}.ts"),
+                init: None,
+                media_start: Some(start_pts),
+            },
+            start_pts,
+            modified,
+        }
+    }
+
+    #[test]
+    pub fn test_place() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let seconds = |s: i64| t1 + TimeDelta::seconds(s);
+
+        let chunks = vec![
+            // Uploaded long after they were recorded, but the last one is still on disk.
+            chunk(2, 115.0, seconds(3600)),
+            chunk(1, 100.0, seconds(3600)),
+            chunk(3, 130.0, seconds(45)),
+            // The camera restarted its clock after a reconnect.
+            chunk(4, 0.0, seconds(80)),
+            chunk(5, 15.0, seconds(95)),
+        ];
+
+        let (files, gaps) = place(chunks);
+        let placed: Vec<(String, DateTime<Utc>)> = files
+            .into_iter()
+            .map(|timed| (timed.file.id, timed.start_time))
+            .collect();
+        assert_eq!(
+            placed,
+            vec![
+                ("000000001.ts".to_string(), seconds(0)),
+                ("000000002.ts".to_string(), seconds(15)),
+                ("000000003.ts".to_string(), seconds(30)),
+                ("000000004.ts".to_string(), seconds(65)),
+                ("000000005.ts".to_string(), seconds(80)),
+            ]
+        );
+        assert_eq!(
+            gaps,
+            vec![Gap {
+                start_time: seconds(45),
+                end_time: seconds(65),
+            }]
+        );
+    }
+
+    #[test]
+    pub fn test_place_late_session() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let seconds = |s: i64| t1 + TimeDelta::seconds(s);
+
+        // Both sessions only survive remotely, and the first one was uploaded late. The second
+        // session can't have started before the first one ended.
+        let chunks = vec![chunk(1, 0.0, seconds(60)), chunk(2, 500.0, seconds(40))];
+
+        let (files, gaps) = place(chunks);
+        assert_eq!(files[0].start_time, seconds(45));
+        assert_eq!(files[1].start_time, seconds(60));
+        assert!(gaps.is_empty());
+    }
+}
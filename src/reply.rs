@@ -1,33 +1,339 @@
+use chrono::SecondsFormat;
 use warp::reply::Response;
 use warp::{http, Reply};
 
 use crate::playlist::{Playlist, PlaylistKind};
 
-impl Reply for Playlist {
-    fn into_response(self) -> Response {
+impl Playlist {
+    pub fn render(&self) -> String {
         let mut body = String::new();
         body.push_str("#EXTM3U\r\n");
 
         if matches!(self.kind, PlaylistKind::VOD) {
             body.push_str("#EXT-X-PLAYLIST-TYPE:VOD\r\n");
         }
-        body.push_str("#EXT-X-TARGETDURATION:15\r\n");
-        body.push_str("#EXT-X-VERSION:4\r\n");
-        body.push_str("#EXT-X-MEDIA-SEQUENCE:1\r\n");
+        // EXT-X-MAP for fragmented MP4 files needs protocol version 6.
+        let version = if self.files.iter().any(|timed| timed.file.init.is_some()) {
+            6
+        } else {
+            4
+        };
+        body.push_str(format!("#EXT-X-TARGETDURATION:{}\r\n", self.target_duration()).as_str());
+        body.push_str(format!("#EXT-X-VERSION:{version}\r\n").as_str());
+        body.push_str(format!("#EXT-X-MEDIA-SEQUENCE:{}\r\n", self.media_sequence).as_str());
+        if self.discontinuity_sequence > 0 {
+            body.push_str(
+                format!(
+                    "#EXT-X-DISCONTINUITY-SEQUENCE:{}\r\n",
+                    self.discontinuity_sequence
+                )
+                .as_str(),
+            );
+        }
         body.push_str("\r\n");
 
-        for file in self.files {
-            body.push_str(format!("#EXTINF:{}\r\n", file.duration).as_str());
-            body.push_str(format!("files/{}\r\n", file.id.as_str()).as_str());
+        let mut previous = None;
+        for timed in &self.files {
+            let discontinuity =
+                previous.is_some_and(|previous| self.is_discontinuity(previous, timed));
+            if discontinuity {
+                body.push_str("#EXT-X-DISCONTINUITY\r\n");
+            }
+
+            // The init segment only changes at a discontinuity.
+            if let Some(init) = &timed.file.init {
+                if previous.is_none() || discontinuity {
+                    body.push_str(format!("#EXT-X-MAP:URI=\"files/{init}\"\r\n").as_str());
+                }
+            }
+
+            let program_date_time = timed
+                .start_time
+                .to_rfc3339_opts(SecondsFormat::Millis, true);
+            body.push_str(format!("#EXT-X-PROGRAM-DATE-TIME:{program_date_time}\r\n").as_str());
+            body.push_str(format!("#EXTINF:{},\r\n", timed.file.duration).as_str());
+            body.push_str(format!("files/{}\r\n", timed.file.id.as_str()).as_str());
+
+            previous = Some(timed);
         }
 
         if matches!(self.kind, PlaylistKind::VOD) {
             body.push_str("#EXT-X-ENDLIST\r\n");
         }
 
+        body
+    }
+}
+
+impl Reply for Playlist {
+    fn into_response(self) -> Response {
         http::Response::builder()
             .header("content-type", "application/x-mpegURL")
-            .body(body.into())
+            .body(self.render().into())
             .unwrap()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use crate::playlist::{Gap, Playlist, PlaylistFile, PlaylistKind, TimedFile};
+
+    fn timed(start_time: DateTime<Utc>, id: &str) -> TimedFile {
+        TimedFile {
+            start_time,
+            file: PlaylistFile {
+                duration: 15.0,
+                id: id.to_string(),
+                init: None,
+                media_start: None,
+            },
+        }
+    }
+
+    /// Check `body` against the media playlist rules of RFC 8216 that apply to our playlists.
+    fn validate(body: &str) {
+        let lines: Vec<&str> = body.split("\r\n").filter(|line| !line.is_empty()).collect();
+        assert_eq!(
+            lines.first(),
+            Some(&"#EXTM3U"),
+            "playlist must start with EXTM3U"
+        );
+
+        let tag = |name: &str| -> Vec<&str> {
+            lines
+                .iter()
+                .filter_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .collect()
+        };
+        let single = |name: &str| -> Option<&str> {
+            let values = tag(name);
+            assert!(values.len() <= 1, "{name} must appear at most once");
+            values.first().copied()
+        };
+
+        let target_duration: f64 = single("#EXT-X-TARGETDURATION")
+            .expect("EXT-X-TARGETDURATION is required")
+            .parse::<u64>()
+            .expect("EXT-X-TARGETDURATION must be a decimal integer")
+            as f64;
+        let version: u64 = single("#EXT-X-VERSION").map_or(1, |v| v.parse().unwrap());
+        single("#EXT-X-MEDIA-SEQUENCE").map(|v| v.parse::<u64>().unwrap());
+        single("#EXT-X-DISCONTINUITY-SEQUENCE").map(|v| v.parse::<u64>().unwrap());
+
+        let first_segment = lines
+            .iter()
+            .position(|line| line.starts_with("#EXTINF:"))
+            .unwrap_or(lines.len());
+        for name in ["#EXT-X-MEDIA-SEQUENCE:", "#EXT-X-DISCONTINUITY-SEQUENCE:"] {
+            if let Some(position) = lines.iter().position(|line| line.starts_with(name)) {
+                assert!(
+                    position < first_segment,
+                    "{name} must precede the first segment"
+                );
+            }
+        }
+
+        if !tag("#EXT-X-MAP").is_empty() {
+            assert!(version >= 6, "EXT-X-MAP requires version 6");
+        }
+        for program_date_time in tag("#EXT-X-PROGRAM-DATE-TIME") {
+            DateTime::parse_from_rfc3339(program_date_time)
+                .expect("EXT-X-PROGRAM-DATE-TIME must be an ISO 8601 date and time");
+        }
+
+        for (i, line) in lines.iter().enumerate() {
+            if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+                let (duration, _title) = extinf
+                    .split_once(',')
+                    .expect("EXTINF must be <duration>,[<title>]");
+                let duration: f64 = duration.parse().expect("EXTINF duration must be a number");
+                assert!(
+                    duration.round() <= target_duration,
+                    "segment of {duration}s exceeds target duration of {target_duration}s"
+                );
+                assert!(
+                    lines.get(i + 1).is_some_and(|uri| !uri.starts_with('#')),
+                    "EXTINF must be followed by a URI"
+                );
+            }
+        }
+
+        if let Some(position) = lines.iter().position(|line| *line == "#EXT-X-ENDLIST") {
+            assert_eq!(
+                position,
+                lines.len() - 1,
+                "nothing may follow EXT-X-ENDLIST"
+            );
+        }
+        if tag("#EXT-X-PLAYLIST-TYPE") == ["VOD"] {
+            assert_eq!(
+                lines.last(),
+                Some(&"#EXT-X-ENDLIST"),
+                "VOD playlists must end"
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_target_duration() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let segment = |offset: i64, seq_num: u64, duration: f64| TimedFile {
+            start_time: t1 + TimeDelta::milliseconds(offset),
+            file: PlaylistFile {
+                duration,
+                id: format!("{seq_num:0>9}.ts"),
+                init: None,
+                media_start: None,
+            },
+        };
+        // Recorded with a 30s roll, one chunk overshot waiting for a keyframe.
+        let playlist = Playlist {
+            kind: PlaylistKind::LIVE,
+            media_sequence: 41,
+            discontinuity_sequence: 0,
+            files: vec![
+                segment(0, 41, 30.0),
+                segment(30_000, 42, 32.4),
+                segment(62_400, 43, 30.0),
+            ],
+            gaps: Vec::new(),
+            max_duration: None,
+        };
+
+        let body = playlist.render();
+        validate(&body);
+        assert!(body.contains("#EXT-X-TARGETDURATION:33\r\n"));
+        assert!(body.contains("#EXT-X-MEDIA-SEQUENCE:41\r\n"));
+
+        // Live playlists keep to the configured bound, whichever files are in the window.
+        let bounded = Playlist {
+            max_duration: Some(40),
+            ..playlist.clone()
+        };
+        let body = bounded.render();
+        validate(&body);
+        assert!(body.contains("#EXT-X-TARGETDURATION:40\r\n"));
+
+        // Overrunning the bound by less than half a second still fits it.
+        let overrun = Playlist {
+            max_duration: Some(32),
+            ..playlist
+        };
+        let body = overrun.render();
+        validate(&body);
+        assert!(body.contains("#EXT-X-TARGETDURATION:32\r\n"));
+    }
+
+    #[test]
+    pub fn test_empty() {
+        let playlist = Playlist {
+            kind: PlaylistKind::VOD,
+            media_sequence: 1,
+            discontinuity_sequence: 0,
+            files: Vec::new(),
+            gaps: Vec::new(),
+            max_duration: None,
+        };
+
+        let body = playlist.render();
+        validate(&body);
+        assert!(body.contains("#EXT-X-TARGETDURATION:1\r\n"));
+    }
+
+    #[test]
+    pub fn test_program_date_time() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let playlist = Playlist {
+            kind: PlaylistKind::VOD,
+            media_sequence: 1,
+            discontinuity_sequence: 0,
+            files: vec![
+                timed(t1, "0001.ts"),
+                timed(t1 + TimeDelta::milliseconds(15_100), "0002.ts"),
+            ],
+            gaps: Vec::new(),
+            max_duration: None,
+        };
+
+        let body = playlist.render();
+        validate(&body);
+        assert!(body.contains(
+            "#EXT-X-PROGRAM-DATE-TIME:2000-01-01T00:00:00.000Z\r\n#EXTINF:15,\r\nfiles/0001.ts\r\n"
+        ));
+        assert!(body.contains("#EXT-X-PROGRAM-DATE-TIME:2000-01-01T00:00:15.100Z\r\n"));
+        assert!(!body.contains("#EXT-X-DISCONTINUITY"));
+    }
+
+    #[test]
+    pub fn test_discontinuities() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let playlist = Playlist {
+            kind: PlaylistKind::LIVE,
+            media_sequence: 10,
+            discontinuity_sequence: 2,
+            files: vec![
+                timed(t1, "0010.ts"),
+                // The camera dropped out for a moment, and was reconnected right away.
+                timed(t1 + TimeDelta::seconds(15), "0011.ts"),
+                // Nothing was recorded for a while, e.g. while the recorder was down.
+                timed(t1 + TimeDelta::seconds(120), "0012.ts"),
+                timed(t1 + TimeDelta::seconds(135), "0013.ts"),
+            ],
+            gaps: vec![Gap {
+                start_time: t1 + TimeDelta::seconds(15),
+                end_time: t1 + TimeDelta::seconds(15),
+            }],
+            max_duration: None,
+        };
+
+        let body = playlist.render();
+        validate(&body);
+        assert!(body.contains("#EXT-X-DISCONTINUITY-SEQUENCE:2\r\n"));
+        assert_eq!(body.matches("#EXT-X-DISCONTINUITY\r\n").count(), 2);
+        assert!(body.contains(
+            "#EXT-X-DISCONTINUITY\r\n#EXT-X-PROGRAM-DATE-TIME:2000-01-01T00:00:15.000Z\r\n"
+        ));
+        assert!(body.contains(
+            "#EXT-X-DISCONTINUITY\r\n#EXT-X-PROGRAM-DATE-TIME:2000-01-01T00:02:00.000Z\r\n"
+        ));
+    }
+
+    #[test]
+    pub fn test_init_segments() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let with_init = |start_time, id: &str, init: &str| TimedFile {
+            start_time,
+            file: PlaylistFile {
+                duration: 15.0,
+                id: id.to_string(),
+                init: Some(init.to_string()),
+                media_start: None,
+            },
+        };
+        let playlist = Playlist {
+            kind: PlaylistKind::VOD,
+            media_sequence: 1,
+            discontinuity_sequence: 0,
+            files: vec![
+                with_init(t1, "0001.m4s", "init-a.mp4"),
+                with_init(t1 + TimeDelta::seconds(15), "0002.m4s", "init-a.mp4"),
+                with_init(t1 + TimeDelta::seconds(30), "0003.m4s", "init-b.mp4"),
+            ],
+            gaps: Vec::new(),
+            max_duration: None,
+        };
+
+        let body = playlist.render();
+        validate(&body);
+        assert!(body.contains("#EXT-X-VERSION:6\r\n"));
+        assert_eq!(
+            body.matches("#EXT-X-MAP:URI=\"files/init-a.mp4\"").count(),
+            1
+        );
+        assert!(body.contains("#EXT-X-DISCONTINUITY\r\n#EXT-X-MAP:URI=\"files/init-b.mp4\"\r\n"));
+    }
+}
@@ -0,0 +1,524 @@
+//! Retention of recorded footage.
+//!
+//! Chunks are kept in two tiers, the local recordings directory and the remote store, each with
+//! its own [`RetentionPolicy`]. The [`Pruner`] periodically deletes whatever falls outside of
+//! them, and forgets about chunks in the database once they're gone from both tiers.
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::chunk::is_chunk;
+use crate::db::{Database, DbError};
+use crate::upload::queue::UploadStatus;
+use crate::upload::tiered::TieredUploader;
+use crate::upload::{CameraUploaders, StoredChunk, Uploader};
+
+/// Limits on how much footage to keep in one storage tier. Chunks are deleted oldest first until
+/// all limits are met.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub max_age: Option<TimeDelta>,
+    pub max_bytes: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age.is_none() && self.max_bytes.is_none()
+    }
+
+    /// The chunks to delete to satisfy this policy. Chunks for which `can_delete` returns false
+    /// are kept, but still count towards the size limit.
+    pub fn expired(
+        &self,
+        mut chunks: Vec<StoredChunk>,
+        now: DateTime<Utc>,
+        can_delete: impl Fn(&StoredChunk) -> bool,
+    ) -> Vec<StoredChunk> {
+        chunks.sort_by(|a, b| (a.last_modified, &a.name).cmp(&(b.last_modified, &b.name)));
+
+        let cutoff = self.max_age.map(|max_age| now - max_age);
+        let mut total: u64 = chunks.iter().map(|chunk| chunk.size as u64).sum();
+
+        let mut expired = Vec::new();
+        for chunk in chunks {
+            let too_old = cutoff.is_some_and(|cutoff| chunk.last_modified < cutoff);
+            let too_big = self.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+
+            if (too_old || too_big) && can_delete(&chunk) {
+                total -= chunk.size as u64;
+                expired.push(chunk);
+            }
+        }
+
+        expired
+    }
+}
+
+/// Retention for both storage tiers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub local: RetentionPolicy,
+    pub remote: RetentionPolicy,
+}
+
+/// Configuration of [`Retention`], from the command line or the `[retention]` table of the config
+/// file. Footage is kept forever by default.
+#[derive(Debug, Clone, Default, clap::Args, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionArgs {
+    /// Delete chunks from local disk once they're older than this many days.
+    #[clap(long, env = "CAMERARS_LOCAL_MAX_AGE_DAYS")]
+    pub local_max_age_days: Option<u32>,
+    /// Delete the oldest chunks from local disk while they take up more than this many bytes.
+    #[clap(long, env = "CAMERARS_LOCAL_MAX_BYTES")]
+    pub local_max_bytes: Option<u64>,
+    /// Delete chunks from remote storage once they're older than this many days.
+    #[clap(long, env = "CAMERARS_REMOTE_MAX_AGE_DAYS")]
+    pub remote_max_age_days: Option<u32>,
+    /// Delete the oldest chunks from remote storage while they take up more than this many bytes.
+    #[clap(long, env = "CAMERARS_REMOTE_MAX_BYTES")]
+    pub remote_max_bytes: Option<u64>,
+    /// Only log what would be pruned, without deleting anything. `--prune-dry-run=false` turns it
+    /// back off when the config file enables it.
+    #[clap(
+        long,
+        env = "CAMERARS_PRUNE_DRY_RUN",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    pub prune_dry_run: Option<bool>,
+}
+
+impl RetentionArgs {
+    /// These arguments, with anything missing taken from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            local_max_age_days: self.local_max_age_days.or(fallback.local_max_age_days),
+            local_max_bytes: self.local_max_bytes.or(fallback.local_max_bytes),
+            remote_max_age_days: self.remote_max_age_days.or(fallback.remote_max_age_days),
+            remote_max_bytes: self.remote_max_bytes.or(fallback.remote_max_bytes),
+            prune_dry_run: self.prune_dry_run.or(fallback.prune_dry_run),
+        }
+    }
+}
+
+impl From<&RetentionArgs> for Retention {
+    fn from(args: &RetentionArgs) -> Self {
+        let days = |days: u32| TimeDelta::days(days.into());
+
+        Self {
+            local: RetentionPolicy {
+                max_age: args.local_max_age_days.map(days),
+                max_bytes: args.local_max_bytes,
+            },
+            remote: RetentionPolicy {
+                max_age: args.remote_max_age_days.map(days),
+                max_bytes: args.remote_max_bytes,
+            },
+        }
+    }
+}
+
+/// What a single pruning pass deleted, or would have deleted in a dry run.
+#[derive(Debug, Default, PartialEq)]
+pub struct PruneReport {
+    pub local: Vec<StoredChunk>,
+    pub remote: Vec<StoredChunk>,
+    /// Chunks that are gone from both tiers, and were removed from the database.
+    pub forgotten: Vec<String>,
+}
+
+impl PruneReport {
+    pub fn is_empty(&self) -> bool {
+        self.local.is_empty() && self.remote.is_empty()
+    }
+}
+
+/// Deletes footage that's past its retention.
+#[derive(Clone)]
+pub struct Pruner {
+    db: Database,
+    retention: Retention,
+    interval: Duration,
+    dry_run: bool,
+}
+
+impl Pruner {
+    pub fn new(db: &Database, retention: Retention) -> Self {
+        Self {
+            db: db.clone(),
+            retention,
+            interval: Duration::from_secs(600),
+            dry_run: false,
+        }
+    }
+
+    /// How long to wait between pruning passes.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+
+        self
+    }
+
+    /// Log what would be deleted, instead of deleting it.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+
+        self
+    }
+
+    /// Prune every camera forever.
+    pub async fn run<U: Uploader>(self, uploaders: CameraUploaders<TieredUploader<U>>) {
+        if self.retention.local.is_unlimited() && self.retention.remote.is_unlimited() {
+            info!("no retention configured, keeping footage forever");
+            return;
+        }
+
+        loop {
+            for (camera_id, uploader) in uploaders.iter() {
+                if let Err(e) = self.prune(camera_id, uploader).await {
+                    warn!(camera = camera_id, error = %e, "failed to prune footage");
+                }
+            }
+
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    /// Run a single pruning pass over one camera.
+    pub async fn prune<U: Uploader>(
+        &self,
+        camera_id: &str,
+        uploader: &TieredUploader<U>,
+    ) -> anyhow::Result<PruneReport> {
+        let now = Utc::now();
+        let local = uploader.list_local_chunks().await?;
+        // Init segments are left alone, they're tiny and shared by many chunks.
+        let mut remote = uploader.remote().list_chunks().await?;
+        remote.retain(|chunk| is_chunk(Path::new(&chunk.name)));
+
+        // The newest chunk stays on disk, it may still be being written and the chunk writer
+        // continues numbering from it after a restart.
+        let newest = local.iter().map(|chunk| chunk.name.clone()).max();
+        let local_expired = self.retention.local.expired(local.clone(), now, |chunk| {
+            Some(&chunk.name) != newest.as_ref()
+                && matches!(
+                    self.db.upload_status(camera_id, &chunk.name),
+                    Ok(Some(UploadStatus::Done | UploadStatus::Pruned))
+                )
+        });
+        let remote_expired = self.retention.remote.expired(remote.clone(), now, |_| true);
+
+        let deleted: HashSet<&str> = local_expired
+            .iter()
+            .chain(&remote_expired)
+            .map(|chunk| chunk.name.as_str())
+            .collect();
+        let remaining: HashSet<&str> = local
+            .iter()
+            .filter(|chunk| !local_expired.contains(chunk))
+            .chain(
+                remote
+                    .iter()
+                    .filter(|chunk| !remote_expired.contains(chunk)),
+            )
+            .map(|chunk| chunk.name.as_str())
+            .collect();
+        let mut forgotten: Vec<String> = deleted
+            .difference(&remaining)
+            .map(|name| name.to_string())
+            .collect();
+        forgotten.sort();
+
+        let report = PruneReport {
+            local: local_expired,
+            remote: remote_expired,
+            forgotten,
+        };
+        if report.is_empty() {
+            return Ok(report);
+        }
+
+        if self.dry_run {
+            for chunk in &report.local {
+                info!(
+                    camera = camera_id,
+                    name = chunk.name,
+                    "would delete local chunk"
+                );
+            }
+            for chunk in &report.remote {
+                info!(
+                    camera = camera_id,
+                    name = chunk.name,
+                    "would delete remote chunk"
+                );
+            }
+            return Ok(report);
+        }
+
+        // Chunks are forgotten as soon as they're gone from their last tier, so that a delete
+        // failing halfway through doesn't leave rows behind for chunks no later pass will see.
+        let deleted_remotely: HashSet<&str> = report
+            .remote
+            .iter()
+            .map(|chunk| chunk.name.as_str())
+            .collect();
+        for chunk in &report.local {
+            uploader.delete_local_chunk(&chunk.name).await?;
+            if !deleted_remotely.contains(chunk.name.as_str()) {
+                self.forget(camera_id, &report, &chunk.name)?;
+            }
+        }
+        for chunk in &report.remote {
+            uploader.remote().delete_chunk(&chunk.name).await?;
+            // Chunks still on disk are forgotten by whichever of retention and the disk quota
+            // deletes them later.
+            self.db.prune_upload(camera_id, &chunk.name)?;
+            self.forget(camera_id, &report, &chunk.name)?;
+        }
+
+        info!(
+            camera = camera_id,
+            local = report.local.len(),
+            remote = report.remote.len(),
+            "pruned expired chunks"
+        );
+
+        Ok(report)
+    }
+
+    /// Remove a deleted chunk from the database, if it's gone from both tiers.
+    fn forget(&self, camera_id: &str, report: &PruneReport, name: &str) -> Result<(), DbError> {
+        if !report.forgotten.iter().any(|forgotten| forgotten == name) {
+            return Ok(());
+        }
+
+        self.db.remove_files(camera_id, &[name.to_string()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Range;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use object_store::memory::InMemory;
+
+    use crate::db::Database;
+    use crate::playlist::PlaylistFile;
+    use crate::retention::{Pruner, Retention, RetentionPolicy};
+    use crate::test_util::{block_on, scratch_dir};
+    use crate::upload::s3::ObjectStoreUploader;
+    use crate::upload::tiered::TieredUploader;
+    use crate::upload::{ReadError, StoredChunk, Uploader};
+
+    const CAMERA: &str = "default";
+
+    fn chunk(name: &str, size: usize, last_modified: DateTime<Utc>) -> StoredChunk {
+        StoredChunk {
+            name: name.to_string(),
+            size,
+            last_modified,
+        }
+    }
+
+    #[test]
+    pub fn test_policy() {
+        let now = DateTime::<Utc>::from_str("2000-01-10 00:00:00Z").unwrap();
+        let chunks = vec![
+            chunk("3.ts", 10, now - TimeDelta::days(1)),
+            chunk("1.ts", 10, now - TimeDelta::days(3)),
+            chunk("2.ts", 10, now - TimeDelta::days(2)),
+        ];
+        let names = |chunks: Vec<StoredChunk>| -> Vec<String> {
+            chunks.into_iter().map(|chunk| chunk.name).collect()
+        };
+
+        let unlimited = RetentionPolicy::default();
+        assert!(unlimited.expired(chunks.clone(), now, |_| true).is_empty());
+
+        let by_age = RetentionPolicy {
+            max_age: Some(TimeDelta::hours(36)),
+            max_bytes: None,
+        };
+        assert_eq!(
+            names(by_age.expired(chunks.clone(), now, |_| true)),
+            vec!["1.ts", "2.ts"]
+        );
+
+        let by_size = RetentionPolicy {
+            max_age: None,
+            max_bytes: Some(15),
+        };
+        assert_eq!(
+            names(by_size.expired(chunks.clone(), now, |_| true)),
+            vec!["1.ts", "2.ts"]
+        );
+        // Chunks that can't be deleted still take up space.
+        assert_eq!(
+            names(by_size.expired(chunks, now, |chunk| chunk.name != "1.ts")),
+            vec!["2.ts", "3.ts"]
+        );
+    }
+
+    /// Remote storage that fails to delete one chunk.
+    #[derive(Clone)]
+    struct FailingDelete {
+        inner: ObjectStoreUploader,
+        name: &'static str,
+    }
+
+    impl Uploader for FailingDelete {
+        async fn upload_chunk(&self, name: &str, chunk: Vec<u8>) -> anyhow::Result<()> {
+            self.inner.upload_chunk(name, chunk).await
+        }
+
+        async fn read_chunk(&self, name: &str) -> Result<Vec<u8>, ReadError> {
+            self.inner.read_chunk(name).await
+        }
+
+        async fn chunk_size(&self, name: &str) -> Result<usize, ReadError> {
+            self.inner.chunk_size(name).await
+        }
+
+        async fn read_chunk_range(
+            &self,
+            name: &str,
+            range: Range<usize>,
+        ) -> Result<Vec<u8>, ReadError> {
+            self.inner.read_chunk_range(name, range).await
+        }
+
+        async fn list_chunks(&self) -> anyhow::Result<Vec<StoredChunk>> {
+            self.inner.list_chunks().await
+        }
+
+        async fn delete_chunk(&self, name: &str) -> anyhow::Result<()> {
+            if name == self.name {
+                anyhow::bail!("failed to delete {name}");
+            }
+            self.inner.delete_chunk(name).await
+        }
+    }
+
+    /// Record three chunks, both locally and remotely, oldest first.
+    async fn record_chunks<U: Uploader>(db: &Database, uploader: &TieredUploader<U>) {
+        for name in ["000000001.ts", "000000002.ts", "000000003.ts"] {
+            let path = uploader.directory().join(name);
+            std::fs::write(&path, b"chunk").unwrap();
+            db.append_file(
+                CAMERA,
+                Utc::now(),
+                PlaylistFile {
+                    id: name.to_string(),
+                    duration: 15.0,
+                    init: None,
+                    media_start: None,
+                },
+            )
+            .unwrap();
+            uploader
+                .upload_chunk(name, b"chunk".to_vec())
+                .await
+                .unwrap();
+            db.enqueue_upload(CAMERA, name, &path, Utc::now()).unwrap();
+            db.complete_upload(CAMERA, name).unwrap();
+
+            // Keep modification times apart so chunks have a well defined order.
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    fn indexed(db: &Database) -> Vec<String> {
+        db.query_files(CAMERA, None, None)
+            .unwrap()
+            .into_iter()
+            .map(|file| file.id)
+            .collect()
+    }
+
+    #[test]
+    pub fn test_prune() {
+        let scratch = scratch_dir();
+        let directory = scratch.path();
+
+        let db = Database::memory();
+        let remote = ObjectStoreUploader::new(Arc::new(InMemory::new()), "");
+        let uploader = TieredUploader::new(directory, remote);
+
+        let retention = Retention {
+            local: RetentionPolicy {
+                max_age: None,
+                max_bytes: Some(0),
+            },
+            remote: RetentionPolicy {
+                max_age: None,
+                max_bytes: Some(10),
+            },
+        };
+
+        block_on(async {
+            record_chunks(&db, &uploader).await;
+
+            let dry_run = Pruner::new(&db, retention).with_dry_run(true);
+            let report = dry_run.prune(CAMERA, &uploader).await.unwrap();
+            assert_eq!(report.local.len(), 2);
+            assert_eq!(report.remote.len(), 1);
+            assert_eq!(report.forgotten, vec!["000000001.ts"]);
+            assert_eq!(uploader.list_local_chunks().await.unwrap().len(), 3);
+            assert_eq!(uploader.remote().list_chunks().await.unwrap().len(), 3);
+            assert_eq!(indexed(&db).len(), 3);
+
+            let pruner = Pruner::new(&db, retention);
+            pruner.prune(CAMERA, &uploader).await.unwrap();
+
+            // Only the newest chunk stays on disk, while the remote keeps the two newest.
+            let local = uploader.list_local_chunks().await.unwrap();
+            assert_eq!(local.len(), 1);
+            assert_eq!(local[0].name, "000000003.ts");
+            assert_eq!(uploader.remote().list_chunks().await.unwrap().len(), 2);
+            assert_eq!(indexed(&db), vec!["000000002.ts", "000000003.ts"]);
+            assert!(uploader.read_chunk("000000002.ts").await.is_ok());
+        });
+    }
+
+    #[test]
+    pub fn test_prune_failed_delete() {
+        let scratch = scratch_dir();
+        let db = Database::memory();
+        let remote = FailingDelete {
+            inner: ObjectStoreUploader::new(Arc::new(InMemory::new()), ""),
+            name: "000000002.ts",
+        };
+        let uploader = TieredUploader::new(scratch.path(), remote);
+
+        let everything = RetentionPolicy {
+            max_age: None,
+            max_bytes: Some(0),
+        };
+        let retention = Retention {
+            local: everything,
+            remote: everything,
+        };
+
+        block_on(async {
+            record_chunks(&db, &uploader).await;
+
+            let pruner = Pruner::new(&db, retention);
+            assert!(pruner.prune(CAMERA, &uploader).await.is_err());
+
+            // The first chunk is gone from both tiers, the second one is still stored remotely.
+            assert_eq!(indexed(&db), vec!["000000002.ts", "000000003.ts"]);
+            assert!(uploader.read_chunk("000000001.ts").await.is_err());
+            assert!(uploader.read_chunk("000000002.ts").await.is_ok());
+        });
+    }
+}
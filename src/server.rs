@@ -1,61 +1,490 @@
 use std::sync::Arc;
 
 use warp::filters::BoxedFilter;
-use warp::{Filter, Reply};
+use warp::{Filter, Rejection, Reply};
 
+use crate::dash::Manifest;
 use crate::execution::PlaylistBuilder;
+use crate::export::{check_range, export};
+use crate::metrics::Metrics;
 use crate::playlist::{OnDemandTimeRange, Playlist};
-use crate::server::types::{TsFile, VodQueryParams};
+use crate::server::error::{handle_rejection, InvalidTimeRange};
+use crate::server::range::ByteRange;
+use crate::server::types::{
+    content_type, ChunkBody, ChunkFile, TimelineQueryParams, VodQueryParams,
+};
 use crate::static_assets::{HLS_JS, PLAYER_HTML};
-use crate::upload::Uploader;
+use crate::upload::{CameraUploaders, Uploader};
 
+mod error;
+pub mod range;
 pub mod types;
 
 /// Server factory, builds a
+///
+/// Only cameras in `uploaders` are served.
 pub fn backend<U: Uploader + 'static>(
     pb: PlaylistBuilder,
-    uploader: Arc<U>,
+    uploaders: CameraUploaders<U>,
+    metrics: Metrics,
 ) -> BoxedFilter<(impl Reply,)> {
-    let uploader = Arc::clone(&uploader);
+    let uploaders = Arc::new(uploaders);
 
-    // file server. uses object_storage directly.
-    let file_route = warp::path!("files" / String)
-        .and(warp::any().map(move || uploader.clone()))
-        .then(file_handler);
+    let cameras = {
+        let mut ids: Vec<String> = uploaders.keys().cloned().collect();
+        ids.sort();
+        ids
+    };
+    let cameras_route = warp::path!("cameras").map(move || warp::reply::json(&cameras));
 
-    let vod_route = warp::path!("vod")
-        .and(warp::query::<VodQueryParams>())
+    // file server. reads from the local recordings first, then object storage.
+    let file_route = {
+        let uploaders = Arc::clone(&uploaders);
+        warp::path!("cameras" / String / "files" / String)
+            .and(warp::header::optional::<String>("range"))
+            .and(warp::any().map(move || Arc::clone(&uploaders)))
+            .and_then(file_handler)
+    };
+
+    let vod_route = {
+        let pb = pb.clone();
+        let uploaders = Arc::clone(&uploaders);
+        warp::path!("cameras" / String / "vod")
+            .and(warp::query::<VodQueryParams>())
+            .and(warp::any().map(move || pb.clone()))
+            .and(warp::any().map(move || Arc::clone(&uploaders)))
+            .and_then(vod_handler)
+    };
+
+    let vod_manifest_route = {
+        let pb = pb.clone();
+        let uploaders = Arc::clone(&uploaders);
+        warp::path!("cameras" / String / "vod.mpd")
+            .and(warp::query::<VodQueryParams>())
+            .and(warp::any().map(move || pb.clone()))
+            .and(warp::any().map(move || Arc::clone(&uploaders)))
+            .and_then(vod_manifest_handler)
+    };
+
+    let live_manifest_route = {
+        let pb = pb.clone();
+        let uploaders = Arc::clone(&uploaders);
+        warp::path!("cameras" / String / "live.mpd")
+            .and(warp::any().map(move || pb.clone()))
+            .and(warp::any().map(move || Arc::clone(&uploaders)))
+            .and_then(live_manifest_handler)
+    };
+
+    let export_route = {
+        let pb = pb.clone();
+        let uploaders = Arc::clone(&uploaders);
+        warp::path!("cameras" / String / "export")
+            .and(warp::query::<VodQueryParams>())
+            .and(warp::any().map(move || pb.clone()))
+            .and(warp::any().map(move || Arc::clone(&uploaders)))
+            .and_then(export_handler)
+    };
+
+    let timeline_route = {
+        let pb = pb.clone();
+        let uploaders = Arc::clone(&uploaders);
+        warp::path!("api" / "timeline")
+            .and(warp::query::<TimelineQueryParams>())
+            .and(warp::any().map(move || pb.clone()))
+            .and(warp::any().map(move || Arc::clone(&uploaders)))
+            .and_then(timeline_handler)
+    };
+
+    let live_route = warp::path!("cameras" / String / "live.m3u8")
         .and(warp::any().map(move || pb.clone()))
-        .then(vod_handler);
+        .and(warp::any().map(move || Arc::clone(&uploaders)))
+        .and_then(live_handler);
+
+    let metrics_route = warp::path!("metrics").map(move || {
+        warp::reply::with_header(
+            metrics.render(),
+            "content-type",
+            "text/plain; version=0.0.4",
+        )
+    });
 
     // Static asset routes
-    let player_route = warp::path::end()
-        .map(|| warp::reply::html(PLAYER_HTML));
+    let player_route = warp::path::end().map(|| warp::reply::html(PLAYER_HTML));
 
     let hls_route = warp::path!("hls.js")
-        .map(|| warp::reply::with_header(
-            HLS_JS,
-            "content-type",
-            "application/javascript"
-        ));
+        .map(|| warp::reply::with_header(HLS_JS, "content-type", "application/javascript"));
 
-    warp::get().and(
-        file_route
-            .or(vod_route)
-            .or(player_route)
-            .or(hls_route)
-    ).boxed()
+    warp::get()
+        .and(
+            cameras_route
+                .or(file_route)
+                .or(vod_route)
+                .or(live_route)
+                .or(vod_manifest_route)
+                .or(live_manifest_route)
+                .or(export_route)
+                .or(timeline_route)
+                .or(metrics_route)
+                .or(player_route)
+                .or(hls_route),
+        )
+        .recover(handle_rejection)
+        .boxed()
 }
 
-async fn file_handler<U: Uploader>(file_id: String, uploader: Arc<U>) -> TsFile {
-    let data = uploader.read_chunk(file_id.as_str()).await;
-    TsFile { data }
+async fn file_handler<U: Uploader>(
+    camera_id: String,
+    file_id: String,
+    range: Option<String>,
+    uploaders: Arc<CameraUploaders<U>>,
+) -> Result<ChunkFile, Rejection> {
+    let uploader = uploaders
+        .get(&camera_id)
+        .ok_or_else(warp::reject::not_found)?;
+
+    let body = match range.as_deref().and_then(ByteRange::parse) {
+        None => ChunkBody::Full {
+            data: uploader.read_chunk(file_id.as_str()).await?,
+        },
+        Some(range) => {
+            let size = uploader.chunk_size(file_id.as_str()).await?;
+            match range.resolve(size) {
+                None => ChunkBody::Unsatisfiable { size },
+                Some(range) => ChunkBody::Partial {
+                    data: uploader
+                        .read_chunk_range(file_id.as_str(), range.clone())
+                        .await?,
+                    range,
+                    size,
+                },
+            }
+        }
+    };
+
+    Ok(ChunkFile {
+        content_type: content_type(&file_id),
+        body,
+    })
 }
 
-async fn vod_handler(vod_params: VodQueryParams, builder: PlaylistBuilder) -> Playlist {
+async fn vod_handler<U: Uploader>(
+    camera_id: String,
+    vod_params: VodQueryParams,
+    builder: PlaylistBuilder,
+    uploaders: Arc<CameraUploaders<U>>,
+) -> Result<Playlist, Rejection> {
+    if !uploaders.contains_key(&camera_id) {
+        return Err(warp::reject::not_found());
+    }
+
     let start = vod_params.start_time;
     let end = vod_params.end_time;
 
     // Construct a new playlist from our output example
-    builder.build_on_demand(OnDemandTimeRange { start, end })
+    Ok(builder.build_on_demand(&camera_id, OnDemandTimeRange { start, end })?)
+}
+
+async fn live_handler<U: Uploader>(
+    camera_id: String,
+    builder: PlaylistBuilder,
+    uploaders: Arc<CameraUploaders<U>>,
+) -> Result<Playlist, Rejection> {
+    if !uploaders.contains_key(&camera_id) {
+        return Err(warp::reject::not_found());
+    }
+
+    Ok(builder.build_live(&camera_id)?)
+}
+
+async fn vod_manifest_handler<U: Uploader>(
+    camera_id: String,
+    vod_params: VodQueryParams,
+    builder: PlaylistBuilder,
+    uploaders: Arc<CameraUploaders<U>>,
+) -> Result<Manifest, Rejection> {
+    if !uploaders.contains_key(&camera_id) {
+        return Err(warp::reject::not_found());
+    }
+
+    let start = vod_params.start_time;
+    let end = vod_params.end_time;
+
+    dash_only(builder.build_on_demand_manifest(&camera_id, OnDemandTimeRange { start, end })?)
+}
+
+async fn live_manifest_handler<U: Uploader>(
+    camera_id: String,
+    builder: PlaylistBuilder,
+    uploaders: Arc<CameraUploaders<U>>,
+) -> Result<Manifest, Rejection> {
+    if !uploaders.contains_key(&camera_id) {
+        return Err(warp::reject::not_found());
+    }
+
+    dash_only(builder.build_live_manifest(&camera_id)?)
+}
+
+/// DASH is only served for fragmented MP4 recordings.
+fn dash_only(manifest: Manifest) -> Result<Manifest, Rejection> {
+    if manifest.has_mpeg_ts() {
+        return Err(warp::reject::not_found());
+    }
+
+    Ok(manifest)
+}
+
+async fn export_handler<U: Uploader>(
+    camera_id: String,
+    params: VodQueryParams,
+    builder: PlaylistBuilder,
+    uploaders: Arc<CameraUploaders<U>>,
+) -> Result<impl Reply, Rejection> {
+    let uploader = uploaders
+        .get(&camera_id)
+        .ok_or_else(warp::reject::not_found)?;
+
+    let start = params.start_time;
+    let end = params.end_time;
+    check_range(start, end)?;
+    let files = builder.clip_files(&camera_id, OnDemandTimeRange { start, end })?;
+    let clip = export(uploader.as_ref(), &files, start, end).await?;
+
+    let file_name = format!("{camera_id}-{}.mp4", start.format("%Y%m%dT%H%M%SZ"));
+    Ok(warp::http::Response::builder()
+        .header("content-type", "video/mp4")
+        .header(
+            "content-disposition",
+            format!("attachment; filename=\"{file_name}\""),
+        )
+        .header("content-length", clip.len)
+        .body(clip.into_body())
+        .unwrap())
+}
+
+async fn timeline_handler<U: Uploader>(
+    params: TimelineQueryParams,
+    builder: PlaylistBuilder,
+    uploaders: Arc<CameraUploaders<U>>,
+) -> Result<impl Reply, Rejection> {
+    if !uploaders.contains_key(&params.camera) {
+        return Err(warp::reject::not_found());
+    }
+    if params.start >= params.end {
+        return Err(warp::reject::custom(InvalidTimeRange));
+    }
+
+    let time_range = OnDemandTimeRange {
+        start: params.start,
+        end: params.end,
+    };
+    Ok(warp::reply::json(
+        &builder.build_timeline(&params.camera, time_range)?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+    use object_store::memory::InMemory;
+    use warp::http::StatusCode;
+
+    use crate::clock::ClockSource;
+    use crate::db::Database;
+    use crate::execution::PlaylistBuilder;
+    use crate::metrics::Metrics;
+    use crate::playlist::PlaylistFile;
+    use crate::server::backend;
+    use crate::test_util::block_on;
+    use crate::upload::s3::ObjectStoreUploader;
+    use crate::upload::{CameraUploaders, Uploader};
+
+    #[test]
+    pub fn test_range_requests() {
+        let uploader = ObjectStoreUploader::new(Arc::new(InMemory::new()), "");
+        let mut uploaders = CameraUploaders::new();
+        uploaders.insert("porch".to_string(), Arc::new(uploader.clone()));
+        let service = backend(
+            PlaylistBuilder::new(&Database::memory()),
+            uploaders,
+            Metrics::new(),
+        );
+
+        block_on(async {
+            uploader
+                .upload_chunk("000000001.ts", b"0123456789".to_vec())
+                .await
+                .unwrap();
+
+            let response = warp::test::request()
+                .path("/cameras/porch/files/000000001.ts")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["accept-ranges"], "bytes");
+            assert_eq!(response.body().as_ref(), b"0123456789");
+
+            let response = warp::test::request()
+                .path("/cameras/porch/files/000000001.ts")
+                .header("range", "bytes=2-4")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+            assert_eq!(response.headers()["content-length"], "3");
+            assert_eq!(response.headers()["content-range"], "bytes 2-4/10");
+            assert_eq!(response.body().as_ref(), b"234");
+
+            let response = warp::test::request()
+                .path("/cameras/porch/files/000000001.ts")
+                .header("range", "bytes=20-")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(response.headers()["content-range"], "bytes */10");
+        });
+    }
+
+    #[test]
+    pub fn test_timeline() {
+        let db = Database::memory();
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        for (seq_num, offset) in [(1, 0), (2, 10), (3, 60)] {
+            db.append_file(
+                "porch",
+                t1 + TimeDelta::seconds(offset),
+                PlaylistFile {
+                    duration: 10.0,
+                    id: format!("{seq_num:0>9}.ts"),
+                    init: None,
+                    media_start: None,
+                },
+            )
+            .unwrap();
+        }
+        db.append_gap(
+            "porch",
+            t1 + TimeDelta::seconds(20),
+            t1 + TimeDelta::seconds(60),
+        )
+        .unwrap();
+
+        let uploader = ObjectStoreUploader::new(Arc::new(InMemory::new()), "");
+        let mut uploaders = CameraUploaders::new();
+        uploaders.insert("porch".to_string(), Arc::new(uploader));
+        let service = backend(PlaylistBuilder::new(&db), uploaders, Metrics::new());
+
+        block_on(async {
+            let response = warp::test::request()
+                .path("/api/timeline?camera=porch&start=2000-01-01T00:00:00Z&end=2000-01-01T00:01:30Z")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["content-type"], "application/json");
+
+            let body = String::from_utf8_lossy(response.body());
+            assert!(body.contains(r#""spans":[{"start_time":"2000-01-01T00:00:00Z","end_time":"2000-01-01T00:00:20Z"},{"start_time":"2000-01-01T00:01:00Z","end_time":"2000-01-01T00:01:10Z"}]"#));
+            assert!(body.contains(r#""gaps":[{"start_time":"2000-01-01T00:00:20Z","end_time":"2000-01-01T00:01:00Z","disconnected":true},{"start_time":"2000-01-01T00:01:10Z","end_time":"2000-01-01T00:01:30Z","disconnected":false}]"#));
+
+            let response = warp::test::request()
+                .path("/api/timeline?camera=driveway&start=2000-01-01T00:00:00Z&end=2000-01-01T00:01:30Z")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            for (start, end) in [("00:01:30", "00:00:00"), ("00:01:30", "00:01:30")] {
+                let response = warp::test::request()
+                    .path(&format!(
+                        "/api/timeline?camera=porch&start=2000-01-01T{start}Z&end=2000-01-01T{end}Z"
+                    ))
+                    .reply(&service)
+                    .await;
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }
+        });
+    }
+
+    #[test]
+    pub fn test_metrics() {
+        let uploaders: CameraUploaders<ObjectStoreUploader> = CameraUploaders::new();
+        let metrics = Metrics::new();
+        metrics.record_clock_drift("porch", ClockSource::Rtcp, TimeDelta::milliseconds(-120));
+        let service = backend(
+            PlaylistBuilder::new(&Database::memory()),
+            uploaders,
+            metrics,
+        );
+
+        block_on(async {
+            let response = warp::test::request().path("/metrics").reply(&service).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = String::from_utf8_lossy(response.body());
+            assert!(body
+                .contains(r#"camerars_clock_drift_seconds{camera="porch",source="rtcp"} -0.12"#));
+        });
+    }
+
+    #[test]
+    pub fn test_errors() {
+        let uploader = ObjectStoreUploader::new(Arc::new(InMemory::new()), "");
+        let mut uploaders = CameraUploaders::new();
+        uploaders.insert("porch".to_string(), Arc::new(uploader));
+        let db = Database::memory();
+        db.append_file(
+            "porch",
+            DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap(),
+            PlaylistFile {
+                id: "000000001.ts".to_string(),
+                duration: 15.0,
+                init: None,
+                media_start: Some(0.0),
+            },
+        )
+        .unwrap();
+        let service = backend(PlaylistBuilder::new(&db), uploaders, Metrics::new());
+
+        block_on(async {
+            // DASH can't serve MPEG-TS.
+            let response = warp::test::request()
+                .path("/cameras/porch/live.mpd")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let response = warp::test::request()
+                .path("/cameras/porch/files/missing.ts")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(response.headers()["content-type"], "application/json");
+
+            let response = warp::test::request()
+                .path("/cameras/driveway/vod?start_time=2000-01-01T00:00:00Z&end_time=2000-01-01T01:00:00Z")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let response = warp::test::request()
+                .path("/cameras/porch/vod?start_time=yesterday")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert!(String::from_utf8_lossy(response.body()).contains("\"status\":400"));
+
+            let response = warp::test::request()
+                .path("/cameras/porch/export?start_time=2001-01-01T00:00:00Z&end_time=2001-01-01T01:00:00Z")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert!(String::from_utf8_lossy(response.body()).contains("no footage"));
+
+            let response = warp::test::request()
+                .path("/cameras/porch/export?start_time=2001-01-01T00:00:00Z&end_time=2001-01-02T00:00:00Z")
+                .reply(&service)
+                .await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        });
+    }
 }
@@ -0,0 +1,110 @@
+use std::convert::Infallible;
+
+use serde::Serialize;
+use tracing::warn;
+use warp::http::StatusCode;
+use warp::reject::{InvalidQuery, MethodNotAllowed, Reject};
+use warp::{Rejection, Reply};
+
+use crate::db::DbError;
+use crate::export::ExportError;
+use crate::upload::ReadError;
+
+/// Rejection for a chunk that couldn't be read back from storage.
+#[derive(Debug)]
+pub(crate) struct StorageRejection(pub(crate) ReadError);
+
+impl Reject for StorageRejection {}
+
+impl From<ReadError> for Rejection {
+    fn from(value: ReadError) -> Self {
+        warp::reject::custom(StorageRejection(value))
+    }
+}
+
+/// Rejection for a request that failed because the database did.
+#[derive(Debug)]
+pub(crate) struct DatabaseRejection(pub(crate) DbError);
+
+impl Reject for DatabaseRejection {}
+
+impl From<DbError> for Rejection {
+    fn from(value: DbError) -> Self {
+        warp::reject::custom(DatabaseRejection(value))
+    }
+}
+
+/// Rejection for a clip that couldn't be exported.
+#[derive(Debug)]
+pub(crate) struct ExportRejection(pub(crate) ExportError);
+
+impl Reject for ExportRejection {}
+
+impl From<ExportError> for Rejection {
+    fn from(value: ExportError) -> Self {
+        match value {
+            ExportError::Read(e) => e.into(),
+            e => warp::reject::custom(ExportRejection(e)),
+        }
+    }
+}
+
+/// Rejection for a query whose time range doesn't end after it starts.
+#[derive(Debug)]
+pub(crate) struct InvalidTimeRange;
+
+impl Reject for InvalidTimeRange {}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    status: u16,
+    error: String,
+}
+
+/// Turn rejections into JSON error responses with a matching status code.
+pub(crate) async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, error) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(StorageRejection(e)) = rejection.find() {
+        match e {
+            ReadError::NotFound { .. } => (StatusCode::NOT_FOUND, e.to_string()),
+            ReadError::Unavailable(_) => {
+                warn!(error = %e, "failed to read from storage");
+                (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+            }
+        }
+    } else if let Some(DatabaseRejection(e)) = rejection.find() {
+        warn!(error = %e, "failed to query database");
+        (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+    } else if let Some(ExportRejection(e)) = rejection.find() {
+        match e {
+            ExportError::NoFootage => (StatusCode::NOT_FOUND, e.to_string()),
+            ExportError::InvalidRange => (StatusCode::BAD_REQUEST, e.to_string()),
+            _ => {
+                warn!(error = %e, "failed to export clip");
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            }
+        }
+    } else if let Some(InvalidTimeRange) = rejection.find() {
+        (
+            StatusCode::BAD_REQUEST,
+            "time range must end after it starts".to_string(),
+        )
+    } else if let Some(e) = rejection.find::<InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    } else {
+        warn!(rejection = ?rejection, "unhandled rejection");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal server error".to_string(),
+        )
+    };
+
+    let body = ErrorBody {
+        status: status.as_u16(),
+        error,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
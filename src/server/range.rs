@@ -0,0 +1,84 @@
+use std::ops::Range;
+
+/// A single byte range requested through a `Range` header.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum ByteRange {
+    /// `bytes=start-end`, both inclusive.
+    FromTo(usize, usize),
+    /// `bytes=start-`, everything from `start` to the end of the file.
+    From(usize),
+    /// `bytes=-length`, the last `length` bytes of the file.
+    Last(usize),
+}
+
+impl ByteRange {
+    /// Parse a `Range` header value. Only a single range of bytes is supported, for anything else
+    /// this returns `None` and the whole file is served, which RFC 9110 allows.
+    pub(crate) fn parse(header: &str) -> Option<Self> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (start, end) = spec.trim().split_once('-')?;
+        match (start.trim(), end.trim()) {
+            ("", "") => None,
+            ("", length) => Some(ByteRange::Last(length.parse().ok()?)),
+            (start, "") => Some(ByteRange::From(start.parse().ok()?)),
+            (start, end) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                if start > end {
+                    return None;
+                }
+                Some(ByteRange::FromTo(start, end))
+            }
+        }
+    }
+
+    /// The half-open range of bytes to serve from a file of `size` bytes, or `None` if the range
+    /// can't be satisfied.
+    pub(crate) fn resolve(self, size: usize) -> Option<Range<usize>> {
+        let range = match self {
+            ByteRange::FromTo(start, end) => start..end.saturating_add(1).min(size),
+            ByteRange::From(start) => start..size,
+            ByteRange::Last(length) => size.saturating_sub(length)..size,
+        };
+
+        if range.start >= range.end {
+            None
+        } else {
+            Some(range)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::range::ByteRange;
+
+    #[test]
+    pub fn test_parse() {
+        assert_eq!(ByteRange::parse("bytes=0-1"), Some(ByteRange::FromTo(0, 1)));
+        assert_eq!(ByteRange::parse("bytes=100-"), Some(ByteRange::From(100)));
+        assert_eq!(ByteRange::parse("bytes=-500"), Some(ByteRange::Last(500)));
+
+        assert_eq!(ByteRange::parse("bytes=0-1, 5-10"), None);
+        assert_eq!(ByteRange::parse("bytes=10-5"), None);
+        assert_eq!(ByteRange::parse("bytes=-"), None);
+        assert_eq!(ByteRange::parse("items=0-1"), None);
+    }
+
+    #[test]
+    pub fn test_resolve() {
+        assert_eq!(ByteRange::FromTo(0, 1).resolve(1000), Some(0..2));
+        assert_eq!(ByteRange::FromTo(500, 5000).resolve(1000), Some(500..1000));
+        assert_eq!(ByteRange::From(100).resolve(1000), Some(100..1000));
+        assert_eq!(ByteRange::Last(500).resolve(1000), Some(500..1000));
+        assert_eq!(ByteRange::Last(5000).resolve(1000), Some(0..1000));
+        let whole = ByteRange::parse("bytes=0-18446744073709551615").unwrap();
+        assert_eq!(whole.resolve(1000), Some(0..1000));
+
+        assert_eq!(ByteRange::From(1000).resolve(1000), None);
+        assert_eq!(ByteRange::Last(0).resolve(1000), None);
+    }
+}
@@ -1,3 +1,5 @@
+use std::ops::Range;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use warp::reply::Response;
@@ -9,15 +11,65 @@ pub(crate) struct VodQueryParams {
     pub end_time: DateTime<Utc>,
 }
 
-pub(crate) struct TsFile {
-    pub(crate) data: Vec<u8>,
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TimelineQueryParams {
+    pub camera: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Media type of a file served from the recordings, based on its extension.
+pub(crate) fn content_type(file_id: &str) -> &'static str {
+    match file_id.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("m4s") => "video/iso.segment",
+        Some("mp4") => "video/mp4",
+        _ => "video/MP2T",
+    }
+}
+
+pub(crate) struct ChunkFile {
+    pub content_type: &'static str,
+    pub body: ChunkBody,
 }
 
-impl Reply for TsFile {
+pub(crate) enum ChunkBody {
+    /// The whole file.
+    Full { data: Vec<u8> },
+    /// Part of a file of `size` bytes, in response to a `Range` request.
+    Partial {
+        data: Vec<u8>,
+        range: Range<usize>,
+        size: usize,
+    },
+    /// A `Range` request that lies outside the file.
+    Unsatisfiable { size: usize },
+}
+
+impl Reply for ChunkFile {
     fn into_response(self) -> Response {
-        http::Response::builder()
-            .header("content-type", "video/MP2T")
-            .body(self.data.into())
-            .unwrap()
+        let builder = http::Response::builder()
+            .header("content-type", self.content_type)
+            .header("accept-ranges", "bytes");
+
+        match self.body {
+            ChunkBody::Full { data } => builder
+                .header("content-length", data.len())
+                .body(data.into())
+                .unwrap(),
+            ChunkBody::Partial { data, range, size } => builder
+                .status(http::StatusCode::PARTIAL_CONTENT)
+                .header("content-length", data.len())
+                .header(
+                    "content-range",
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                )
+                .body(data.into())
+                .unwrap(),
+            ChunkBody::Unsatisfiable { size } => builder
+                .status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("content-range", format!("bytes */{size}"))
+                .body(Default::default())
+                .unwrap(),
+        }
     }
 }
@@ -0,0 +1,160 @@
+//! Graceful shutdown on SIGTERM and SIGINT.
+//!
+//! A [`Shutdown`] is shared between the pipeline threads, which poll it between packets and
+//! while waiting to reconnect, and the async tasks on the runtime, which await it.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: Mutex<bool>,
+    condvar: Condvar,
+    notify: Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask everything holding this handle to wrap up.
+    pub fn request(&self) {
+        *self.inner.requested.lock().unwrap() = true;
+        self.inner.condvar.notify_all();
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.inner.requested.lock().unwrap()
+    }
+
+    /// Block the current thread for `duration`, or until shutdown is requested. Returns whether
+    /// shutdown was requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let requested = self.inner.requested.lock().unwrap();
+        let (requested, _) = self
+            .inner
+            .condvar
+            .wait_timeout_while(requested, duration, |requested| !*requested)
+            .unwrap();
+
+        *requested
+    }
+
+    /// Resolves once shutdown is requested.
+    pub async fn requested(&self) {
+        // Register before checking, so a request in between can't be missed.
+        let notified = self.inner.notify.notified();
+        if self.is_requested() {
+            return;
+        }
+
+        notified.await
+    }
+
+    /// Request shutdown when the process receives SIGTERM or SIGINT, and exit right away on a
+    /// second one.
+    pub async fn listen_for_signals(self) {
+        let mut signals = Signals::new();
+
+        let signal = signals.recv().await;
+        info!("received {signal}, shutting down");
+        self.request();
+
+        // Draining uploads can take a while, which whoever sent the signal may not want to wait
+        // for. Handling the first signal replaced the default of exiting, so do it ourselves.
+        let signal = signals.recv().await;
+        warn!("received {signal} again, exiting without finishing shutdown");
+        std::process::exit(1);
+    }
+}
+
+/// The signals asking the process to shut down.
+struct Signals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    fn new() -> Self {
+        Self {
+            #[cfg(unix)]
+            terminate: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("installing SIGTERM handler should succeed"),
+        }
+    }
+
+    /// Wait for the next signal, returning its name.
+    async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = self.terminate.recv() => "SIGTERM",
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            tokio::signal::ctrl_c().await.ok();
+            "Ctrl-C"
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::shutdown::Shutdown;
+    use crate::test_util::block_on;
+
+    #[test]
+    pub fn test_sleep() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.sleep(Duration::from_millis(10)));
+
+        let requester = shutdown.clone();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            requester.request();
+        });
+
+        let started = Instant::now();
+        assert!(shutdown.sleep(Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(60));
+        assert!(shutdown.is_requested());
+        thread.join().unwrap();
+    }
+
+    #[test]
+    pub fn test_requested() {
+        let shutdown = Shutdown::new();
+
+        block_on(async {
+            let waiting = tokio::spawn({
+                let shutdown = shutdown.clone();
+                async move { shutdown.requested().await }
+            });
+            tokio::task::yield_now().await;
+            assert!(!waiting.is_finished());
+
+            shutdown.request();
+            tokio::time::timeout(Duration::from_secs(1), waiting)
+                .await
+                .expect("waiters should be woken")
+                .unwrap();
+
+            // Requests before waiting count too.
+            shutdown.requested().await;
+        });
+    }
+}
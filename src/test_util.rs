@@ -0,0 +1,22 @@
+//! Fixtures shared by the tests.
+
+use std::future::Future;
+
+use tempfile::TempDir;
+
+/// A fresh directory for a test to write to, deleted when dropped, also when the test panics.
+pub fn scratch_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("camerars-test-")
+        .tempdir()
+        .unwrap()
+}
+
+/// Run `future` to completion on a single-threaded runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
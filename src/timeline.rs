@@ -0,0 +1,191 @@
+//! Coverage of the recordings over a time range, for drawing a scrubber bar.
+//!
+//! Recorded files are merged into spans of continuous footage, cut off at the requested range.
+//! Everything in between is a gap, whatever the reason nothing was recorded: the camera
+//! disconnected, couldn't be reached at startup, or the recorder wasn't running at all. Gaps the
+//! recorder saw the camera disconnect for are marked as such.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::playlist::{Gap, OnDemandTimeRange, TimedFile, MAX_DRIFT};
+
+/// A period of continuous recording.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Span {
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+}
+
+/// A period without any footage.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Outage {
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// Whether the recorder saw the camera disconnect during it.
+    pub disconnected: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Timeline {
+    pub camera: String,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub spans: Vec<Span>,
+    pub gaps: Vec<Outage>,
+}
+
+impl Timeline {
+    /// Build the timeline of `time_range` from the `files` and disconnection `gaps` overlapping
+    /// it, both ordered by start time.
+    pub fn new(
+        camera_id: &str,
+        time_range: OnDemandTimeRange,
+        files: &[TimedFile],
+        gaps: Vec<Gap>,
+    ) -> Self {
+        let OnDemandTimeRange { start, end } = time_range;
+        let clamp = |time: DateTime<Utc>| time.clamp(start, end);
+
+        let mut spans: Vec<Span> = Vec::new();
+        for timed in files {
+            match spans.last_mut() {
+                Some(span) if timed.start_time - span.end_time <= MAX_DRIFT => {
+                    span.end_time = span.end_time.max(timed.end_time());
+                }
+                _ => spans.push(Span {
+                    start_time: timed.start_time,
+                    end_time: timed.end_time(),
+                }),
+            }
+        }
+
+        let spans: Vec<Span> = spans
+            .into_iter()
+            .map(|span| Span {
+                start_time: clamp(span.start_time),
+                end_time: clamp(span.end_time),
+            })
+            .filter(|span| span.start_time < span.end_time)
+            .collect();
+
+        let mut outages = Vec::new();
+        let mut covered_until = start;
+        let boundaries = spans
+            .iter()
+            .map(|span| (span.start_time, span.end_time))
+            .chain([(end, end)]);
+        for (span_start, span_end) in boundaries {
+            if span_start > covered_until {
+                let disconnected = gaps
+                    .iter()
+                    .any(|gap| gap.start_time <= span_start && gap.end_time >= covered_until);
+                outages.push(Outage {
+                    start_time: covered_until,
+                    end_time: span_start,
+                    disconnected,
+                });
+            }
+            covered_until = covered_until.max(span_end);
+        }
+
+        Self {
+            camera: camera_id.to_string(),
+            start_time: start,
+            end_time: end,
+            spans,
+            gaps: outages,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use chrono::{DateTime, TimeDelta, Utc};
+
+    use crate::playlist::{Gap, PlaylistFile, TimedFile};
+    use crate::timeline::{Outage, Span, Timeline};
+
+    fn timed(start_time: DateTime<Utc>, id: &str) -> TimedFile {
+        TimedFile {
+            start_time,
+            file: PlaylistFile {
+                duration: 10.0,
+                id: id.to_string(),
+                init: None,
+                media_start: None,
+            },
+        }
+    }
+
+    #[test]
+    pub fn test_timeline() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let seconds = |s: i64| t1 + TimeDelta::seconds(s);
+
+        let files = vec![
+            timed(seconds(0), "000000001.ts"),
+            timed(seconds(10) + TimeDelta::milliseconds(200), "000000002.ts"),
+            // Back after an outage.
+            timed(seconds(60), "000000003.ts"),
+            timed(seconds(70), "000000004.ts"),
+        ];
+        let gaps = vec![Gap {
+            start_time: seconds(20),
+            end_time: seconds(60),
+        }];
+
+        let timeline = Timeline::new("porch", (seconds(5), seconds(75)).into(), &files, gaps);
+        assert_eq!(
+            timeline.spans,
+            vec![
+                Span {
+                    start_time: seconds(5),
+                    end_time: seconds(20) + TimeDelta::milliseconds(200),
+                },
+                Span {
+                    start_time: seconds(60),
+                    end_time: seconds(75),
+                },
+            ]
+        );
+        assert_eq!(
+            timeline.gaps,
+            vec![Outage {
+                start_time: seconds(20) + TimeDelta::milliseconds(200),
+                end_time: seconds(60),
+                disconnected: true,
+            }]
+        );
+    }
+
+    #[test]
+    pub fn test_unrecorded() {
+        let t1 = DateTime::<Utc>::from_str("2000-01-01 00:00:00Z").unwrap();
+        let seconds = |s: i64| t1 + TimeDelta::seconds(s);
+
+        // The recorder was down in between, so the camera was never seen to disconnect.
+        let files = vec![
+            timed(seconds(0), "000000001.ts"),
+            timed(seconds(30), "000000002.ts"),
+        ];
+
+        let timeline = Timeline::new("porch", (seconds(-10), seconds(50)).into(), &files, vec![]);
+        assert_eq!(timeline.spans.len(), 2);
+        let outage = |start: i64, end: i64| Outage {
+            start_time: seconds(start),
+            end_time: seconds(end),
+            disconnected: false,
+        };
+        assert_eq!(
+            timeline.gaps,
+            vec![outage(-10, 0), outage(10, 30), outage(40, 50)]
+        );
+
+        // Nothing recorded at all.
+        let timeline = Timeline::new("porch", (seconds(100), seconds(200)).into(), &[], vec![]);
+        assert_eq!(timeline.gaps, vec![outage(100, 200)]);
+    }
+}
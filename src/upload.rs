@@ -1,6 +1,17 @@
+use std::collections::HashMap;
 use std::future::Future;
+use std::ops::Range;
+use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
+
+pub mod queue;
 pub mod s3;
+pub mod storage;
+pub mod tiered;
+
+/// Uploader for each camera, keyed by camera id.
+pub type CameraUploaders<U> = HashMap<String, Arc<U>>;
 
 /// Uploader indicates which uploaders are available, if possible.
 /// We want to support a distributed instance of the Slice to get a list of all available
@@ -13,5 +24,38 @@ pub trait Uploader: Send + Sync + Clone {
     ) -> impl Future<Output = anyhow::Result<()>> + Send;
 
     // Return back a buffer, potentially with a range request to return a set of bytes.
-    fn read_chunk(&self, name: &str) -> impl Future<Output = Vec<u8>> + Send;
+    fn read_chunk(&self, name: &str) -> impl Future<Output = Result<Vec<u8>, ReadError>> + Send;
+
+    /// Size of the chunk in bytes.
+    fn chunk_size(&self, name: &str) -> impl Future<Output = Result<usize, ReadError>> + Send;
+
+    /// Read only the given range of bytes from the chunk.
+    fn read_chunk_range(
+        &self,
+        name: &str,
+        range: Range<usize>,
+    ) -> impl Future<Output = Result<Vec<u8>, ReadError>> + Send;
+
+    /// All chunks currently in storage.
+    fn list_chunks(&self) -> impl Future<Output = anyhow::Result<Vec<StoredChunk>>> + Send;
+
+    fn delete_chunk(&self, name: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// A chunk held by an [`Uploader`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredChunk {
+    pub name: String,
+    pub size: usize,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Failure to read a chunk back from an [`Uploader`].
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    #[error("chunk {name:?} does not exist")]
+    NotFound { name: String },
+
+    #[error("storage is unavailable: {0}")]
+    Unavailable(#[source] Box<dyn std::error::Error + Send + Sync>),
 }
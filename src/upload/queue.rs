@@ -0,0 +1,278 @@
+//! Persistent upload queue.
+//!
+//! Finished chunks are recorded in the [`Database`] and uploaded by a single background worker,
+//! so that uploads survive restarts and remote outages of any length. Failed uploads are retried
+//! with exponential backoff until they succeed; only chunks that disappeared from disk are given
+//! up on.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::backoff::{jitter, Backoff};
+use crate::chunk::{is_chunk, is_init_segment};
+use crate::db::{Database, DbError};
+use crate::upload::{CameraUploaders, Uploader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadStatus {
+    Pending,
+    InProgress,
+    Done,
+    Failed,
+    /// Uploaded, but since deleted from remote storage while the local copy remains.
+    Pruned,
+}
+
+impl UploadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploadStatus::Pending => "pending",
+            UploadStatus::InProgress => "in_progress",
+            UploadStatus::Done => "done",
+            UploadStatus::Failed => "failed",
+            UploadStatus::Pruned => "pruned",
+        }
+    }
+}
+
+impl FromStr for UploadStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(UploadStatus::Pending),
+            "in_progress" => Ok(UploadStatus::InProgress),
+            "done" => Ok(UploadStatus::Done),
+            "failed" => Ok(UploadStatus::Failed),
+            "pruned" => Ok(UploadStatus::Pruned),
+            _ => anyhow::bail!("unknown upload status {s:?}"),
+        }
+    }
+}
+
+/// How often [`UploadQueue::drain`] checks whether the queue is empty.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A chunk waiting to be uploaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadTask {
+    pub camera_id: String,
+    pub file_id: String,
+    pub path: PathBuf,
+    /// Number of failed attempts so far.
+    pub attempts: u32,
+}
+
+/// Handle to the upload queue, shared between the pipelines adding chunks and the worker
+/// uploading them.
+#[derive(Clone)]
+pub struct UploadQueue {
+    db: Database,
+    notify: Arc<Notify>,
+    backoff: Backoff,
+}
+
+impl UploadQueue {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            db: db.clone(),
+            notify: Arc::new(Notify::new()),
+            backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(600)),
+        }
+    }
+
+    /// Delay between retries of a failed upload, doubling from `initial` up to `max`.
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = Backoff::new(initial, max);
+
+        self
+    }
+
+    /// Queue a finished chunk for upload.
+    pub fn enqueue(&self, camera_id: &str, path: &Path) {
+        let Some(file_id) = path.file_name().and_then(|name| name.to_str()) else {
+            warn!("not uploading chunk with invalid file name {path:?}");
+            return;
+        };
+
+        // The chunk is still on disk, so it's picked up again by `recover` on the next start.
+        if let Err(e) = self.db.enqueue_upload(camera_id, file_id, path, Utc::now()) {
+            warn!(error = %e, file = file_id, "failed to queue chunk for upload");
+            return;
+        }
+        self.notify.notify_one();
+    }
+
+    /// Re-queue everything for a camera that wasn't confirmed uploaded before the last shutdown:
+    /// uploads that were interrupted, and chunks in its directory that never made it into the
+    /// queue at all.
+    pub fn recover(&self, camera_id: &str, directory: impl AsRef<Path>) {
+        // Interrupted uploads are only held up until the next start, their chunks are re-queued
+        // below either way.
+        if let Err(e) = self.db.reset_interrupted_uploads(camera_id) {
+            warn!(error = %e, camera = camera_id, "failed to reset interrupted uploads");
+        }
+
+        let Ok(entries) = std::fs::read_dir(directory.as_ref()) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if (is_chunk(&path) || is_init_segment(&path))
+                && entry.file_type().is_ok_and(|file_type| file_type.is_file())
+            {
+                self.enqueue(camera_id, &path);
+            }
+        }
+    }
+
+    /// Drain the queue forever, uploading each chunk with the uploader of its camera. While the
+    /// database is failing, back off and try again.
+    pub async fn run<U: Uploader>(self, uploaders: CameraUploaders<U>) {
+        info!("begin upload worker");
+
+        let mut failures = 0;
+        loop {
+            match self.upload_next(&uploaders).await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    let delay = jitter(self.backoff.delay_after(failures));
+                    warn!(error = %e, "upload queue failed, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Upload the chunk that has been due the longest, or wait until one might be due.
+    async fn upload_next<U: Uploader>(
+        &self,
+        uploaders: &CameraUploaders<U>,
+    ) -> Result<(), DbError> {
+        let Some(task) = self.db.claim_upload(Utc::now())? else {
+            // Sleep until the next retry is due, or a new chunk is queued.
+            let wait = self
+                .db
+                .next_upload_due()?
+                .and_then(|due| (due - Utc::now()).to_std().ok())
+                .unwrap_or(Duration::from_secs(60));
+            tokio::time::timeout(wait, self.notify.notified())
+                .await
+                .ok();
+            return Ok(());
+        };
+
+        let Some(uploader) = uploaders.get(&task.camera_id) else {
+            return self.retry(&task, "no uploader for camera");
+        };
+
+        let chunk = match tokio::fs::read(&task.path).await {
+            Ok(chunk) => chunk,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!(
+                    file = task.file_id,
+                    "chunk is gone from disk, giving up on upload"
+                );
+                return self
+                    .db
+                    .fail_upload(&task.camera_id, &task.file_id, &e.to_string());
+            }
+            Err(e) => return self.retry(&task, &e.to_string()),
+        };
+
+        match uploader.upload_chunk(&task.file_id, chunk).await {
+            Ok(()) => self.db.complete_upload(&task.camera_id, &task.file_id),
+            Err(e) => self.retry(&task, &e.to_string()),
+        }
+    }
+
+    /// Wait up to `timeout` for the worker to finish every queued upload, e.g. before shutting
+    /// down. Returns whether the queue drained in time; whatever is left is picked up again by
+    /// [`UploadQueue::recover`] on the next start.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = match self.db.count_unfinished_uploads() {
+                Ok(0) => return true,
+                Ok(remaining) => remaining,
+                Err(e) => {
+                    warn!(error = %e, "failed to count unfinished uploads at shutdown");
+                    return false;
+                }
+            };
+
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("{remaining} uploads still unfinished at shutdown");
+                return false;
+            }
+            tokio::time::sleep(DRAIN_POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    fn retry(&self, task: &UploadTask, error: &str) -> Result<(), DbError> {
+        let delay = jitter(self.backoff.delay_after(task.attempts + 1));
+        warn!(
+            camera = task.camera_id,
+            file = task.file_id,
+            error = error,
+            "upload attempt {} failed, retrying in {delay:?}",
+            task.attempts + 1
+        );
+
+        let next_attempt = Utc::now() + delay;
+        self.db
+            .retry_upload(&task.camera_id, &task.file_id, error, next_attempt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use object_store::memory::InMemory;
+
+    use crate::db::Database;
+    use crate::test_util::{block_on, scratch_dir};
+    use crate::upload::queue::{UploadQueue, UploadStatus};
+    use crate::upload::s3::ObjectStoreUploader;
+    use crate::upload::{CameraUploaders, Uploader};
+
+    #[test]
+    pub fn test_drain() {
+        let scratch = scratch_dir();
+        let path = scratch.path().join("000000001.ts");
+        std::fs::write(&path, b"chunk").unwrap();
+
+        let db = Database::memory();
+        let uploads = UploadQueue::new(&db);
+        let uploader = ObjectStoreUploader::new(Arc::new(InMemory::new()), "");
+        let mut uploaders = CameraUploaders::new();
+        uploaders.insert("porch".to_string(), Arc::new(uploader.clone()));
+
+        block_on(async {
+            uploads.enqueue("porch", &path);
+
+            // Nothing is uploading yet.
+            assert!(!uploads.drain(Duration::from_millis(10)).await);
+
+            tokio::spawn(uploads.clone().run(uploaders));
+            assert!(uploads.drain(Duration::from_secs(10)).await);
+            assert_eq!(
+                db.upload_status("porch", "000000001.ts").unwrap(),
+                Some(UploadStatus::Done)
+            );
+            assert_eq!(uploader.read_chunk("000000001.ts").await.unwrap(), b"chunk");
+        });
+    }
+}
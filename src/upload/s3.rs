@@ -1,3 +1,4 @@
+use std::ops::Range;
 use std::sync::Arc;
 
 use bytes::Bytes;
@@ -5,7 +6,7 @@ use object_store::path::Path;
 use object_store::ObjectStore;
 use tracing::info;
 
-use crate::upload::Uploader;
+use crate::upload::{ReadError, StoredChunk, Uploader};
 
 #[derive(Clone)]
 pub struct ObjectStoreUploader {
@@ -34,6 +35,11 @@ impl ObjectStoreUploader {
             prefix: prefix.into(),
         }
     }
+
+    /// An uploader sharing the same object store client, but writing under a different prefix.
+    pub fn with_prefix<P: Into<Path>>(&self, prefix: P) -> Self {
+        Self::new(Arc::clone(&self.object_store), prefix)
+    }
 }
 
 impl Uploader for ObjectStoreUploader {
@@ -45,19 +51,92 @@ impl Uploader for ObjectStoreUploader {
         s3_upload_chunk(target_path, chunk, self.object_store.clone()).await
     }
 
-    async fn read_chunk(&self, name: &str) -> Vec<u8> {
+    async fn read_chunk(&self, name: &str) -> Result<Vec<u8>, ReadError> {
         info!(name = name, "Reading chunk from remote storage");
         let target_path = self.prefix.clone().child(name);
 
-        self.object_store
+        let data = self
+            .object_store
             .clone()
             .get(&target_path)
             .await
-            .expect("object_store GET should succeed")
+            .map_err(|e| read_error(name, e))?
             .bytes()
             .await
-            .unwrap()
-            .to_vec()
+            .map_err(|e| read_error(name, e))?;
+
+        Ok(data.to_vec())
+    }
+
+    async fn chunk_size(&self, name: &str) -> Result<usize, ReadError> {
+        let target_path = self.prefix.clone().child(name);
+
+        let meta = self
+            .object_store
+            .head(&target_path)
+            .await
+            .map_err(|e| read_error(name, e))?;
+
+        Ok(meta.size)
+    }
+
+    async fn read_chunk_range(
+        &self,
+        name: &str,
+        range: Range<usize>,
+    ) -> Result<Vec<u8>, ReadError> {
+        info!(
+            name = name,
+            "Reading chunk range {range:?} from remote storage"
+        );
+        let target_path = self.prefix.clone().child(name);
+
+        let data = self
+            .object_store
+            .get_range(&target_path, range)
+            .await
+            .map_err(|e| read_error(name, e))?;
+
+        Ok(data.to_vec())
+    }
+
+    async fn list_chunks(&self) -> anyhow::Result<Vec<StoredChunk>> {
+        // Only list objects directly under the prefix, other cameras may live in sub-prefixes.
+        let listing = self
+            .object_store
+            .list_with_delimiter(Some(&self.prefix))
+            .await?;
+
+        let chunks = listing
+            .objects
+            .into_iter()
+            .filter_map(|meta| {
+                Some(StoredChunk {
+                    name: meta.location.filename()?.to_string(),
+                    size: meta.size,
+                    last_modified: meta.last_modified,
+                })
+            })
+            .collect();
+
+        Ok(chunks)
+    }
+
+    async fn delete_chunk(&self, name: &str) -> anyhow::Result<()> {
+        info!(name = name, "Deleting chunk from remote storage");
+        let target_path = self.prefix.clone().child(name);
+
+        self.object_store.delete(&target_path).await?;
+        Ok(())
+    }
+}
+
+fn read_error(name: &str, error: object_store::Error) -> ReadError {
+    match error {
+        object_store::Error::NotFound { .. } => ReadError::NotFound {
+            name: name.to_string(),
+        },
+        e => ReadError::Unavailable(Box::new(e)),
     }
 }
 
@@ -0,0 +1,193 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Context;
+use clap::ValueEnum;
+use object_store::aws::AmazonS3Builder;
+use object_store::local::LocalFileSystem;
+use object_store::memory::InMemory;
+use object_store::ObjectStore;
+use serde::Deserialize;
+
+use crate::upload::s3::ObjectStoreUploader;
+
+/// Where finished chunks are uploaded to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Storage {
+    /// A local directory, e.g. a mounted NAS share.
+    Local { path: PathBuf },
+    /// Kept in memory only and lost on exit, for tests and trying things out.
+    Memory,
+    /// Amazon S3, with credentials and region taken from the `AWS_*` environment variables.
+    S3 { bucket: Option<String> },
+    /// An S3-compatible service such as MinIO, reached at `endpoint`.
+    S3Compatible {
+        endpoint: String,
+        bucket: Option<String>,
+    },
+}
+
+impl Storage {
+    pub fn build(&self) -> anyhow::Result<Arc<dyn ObjectStore>> {
+        let object_store: Arc<dyn ObjectStore> = match self {
+            Storage::Local { path } => {
+                std::fs::create_dir_all(path)
+                    .with_context(|| format!("failed to create storage directory {path:?}"))?;
+                Arc::new(LocalFileSystem::new_with_prefix(path)?)
+            }
+            Storage::Memory => Arc::new(InMemory::new()),
+            Storage::S3 { bucket } => Arc::new(s3_builder(bucket).build()?),
+            Storage::S3Compatible { endpoint, bucket } => Arc::new(
+                s3_builder(bucket)
+                    .with_endpoint(endpoint)
+                    .with_allow_http(endpoint.starts_with("http://"))
+                    .build()?,
+            ),
+        };
+
+        Ok(object_store)
+    }
+
+    /// Build the object store and an uploader writing under `prefix` in it.
+    pub fn uploader(&self, prefix: &str) -> anyhow::Result<ObjectStoreUploader> {
+        Ok(ObjectStoreUploader::new(self.build()?, prefix))
+    }
+}
+
+fn s3_builder(bucket: &Option<String>) -> AmazonS3Builder {
+    let builder = AmazonS3Builder::from_env();
+    match bucket {
+        Some(bucket) => builder.with_bucket_name(bucket),
+        None => builder,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StorageKind {
+    Local,
+    Memory,
+    S3,
+    S3Compatible,
+}
+
+/// Selection of the [`Storage`] backend, from the command line or the `[storage]` table of the
+/// config file.
+#[derive(Debug, Clone, Default, clap::Args, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageArgs {
+    /// Storage backend finished chunks are uploaded to. Defaults to S3.
+    #[clap(long = "storage", value_enum, env = "CAMERARS_STORAGE")]
+    pub kind: Option<StorageKind>,
+    /// Directory to upload to with `--storage local`.
+    #[clap(long, env = "CAMERARS_STORAGE_PATH")]
+    #[serde(rename = "path")]
+    pub storage_path: Option<PathBuf>,
+    /// Bucket to upload to with S3 storage. Defaults to `AWS_BUCKET` from the environment.
+    #[clap(long, env = "CAMERARS_BUCKET")]
+    pub bucket: Option<String>,
+    /// Endpoint of the service with `--storage s3-compatible`, e.g. `http://localhost:9000`.
+    #[clap(long, env = "CAMERARS_ENDPOINT")]
+    pub endpoint: Option<String>,
+}
+
+impl StorageArgs {
+    /// These arguments, with anything missing taken from `fallback`.
+    pub fn or(self, fallback: Self) -> Self {
+        Self {
+            kind: self.kind.or(fallback.kind),
+            storage_path: self.storage_path.or(fallback.storage_path),
+            bucket: self.bucket.or(fallback.bucket),
+            endpoint: self.endpoint.or(fallback.endpoint),
+        }
+    }
+}
+
+impl TryFrom<StorageArgs> for Storage {
+    type Error = anyhow::Error;
+
+    fn try_from(args: StorageArgs) -> Result<Self, Self::Error> {
+        let storage = match args.kind.unwrap_or(StorageKind::S3) {
+            StorageKind::Local => Storage::Local {
+                path: args
+                    .storage_path
+                    .context("local storage needs a path, set `storage.path` or --storage-path")?,
+            },
+            StorageKind::Memory => Storage::Memory,
+            StorageKind::S3 => Storage::S3 {
+                bucket: args.bucket,
+            },
+            StorageKind::S3Compatible => Storage::S3Compatible {
+                endpoint: args.endpoint.context(
+                    "S3-compatible storage needs an endpoint, set `storage.endpoint` or --endpoint",
+                )?,
+                bucket: args.bucket,
+            },
+        };
+
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::test_util::{block_on, scratch_dir};
+    use crate::upload::storage::{Storage, StorageArgs, StorageKind};
+    use crate::upload::Uploader;
+
+    fn args(kind: StorageKind) -> StorageArgs {
+        StorageArgs {
+            kind: Some(kind),
+            storage_path: None,
+            bucket: None,
+            endpoint: None,
+        }
+    }
+
+    #[test]
+    pub fn test_from_args() {
+        assert_eq!(
+            Storage::try_from(args(StorageKind::Memory)).unwrap(),
+            Storage::Memory
+        );
+        assert!(Storage::try_from(args(StorageKind::Local)).is_err());
+        assert!(Storage::try_from(args(StorageKind::S3Compatible)).is_err());
+
+        let local = StorageArgs {
+            storage_path: Some(PathBuf::from("/mnt/nas")),
+            ..args(StorageKind::Local)
+        };
+        assert_eq!(
+            Storage::try_from(local).unwrap(),
+            Storage::Local {
+                path: PathBuf::from("/mnt/nas")
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_local_storage() {
+        let scratch = scratch_dir();
+        let path = scratch.path();
+        let uploader = Storage::Local {
+            path: path.to_path_buf(),
+        }
+        .uploader("front")
+        .unwrap();
+
+        block_on(async {
+            uploader
+                .upload_chunk("000000001.ts", b"chunk".to_vec())
+                .await
+                .unwrap();
+
+            assert_eq!(uploader.read_chunk("000000001.ts").await.unwrap(), b"chunk");
+        });
+        assert_eq!(
+            std::fs::read(path.join("front").join("000000001.ts")).unwrap(),
+            b"chunk"
+        );
+    }
+}
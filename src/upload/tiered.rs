@@ -0,0 +1,232 @@
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{debug, warn};
+
+use crate::chunk::{is_chunk, is_init_segment};
+use crate::upload::{ReadError, StoredChunk, Uploader};
+
+/// Serves chunks from the local recordings directory while they're still on disk, only going to
+/// the remote store for chunks that aren't. Recent footage stays playable while the remote is
+/// unreachable or a chunk hasn't finished uploading yet.
+///
+/// Uploads always go to the remote store.
+#[derive(Clone)]
+pub struct TieredUploader<U> {
+    directory: PathBuf,
+    remote: U,
+}
+
+impl<U: Uploader> TieredUploader<U> {
+    pub fn new(directory: impl AsRef<Path>, remote: U) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+            remote,
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn remote(&self) -> &U {
+        &self.remote
+    }
+
+    /// Chunks still in the local directory.
+    pub async fn list_local_chunks(&self) -> std::io::Result<Vec<StoredChunk>> {
+        self.list_local(is_chunk).await
+    }
+
+    /// Init segments in the local directory.
+    pub async fn list_local_init_segments(&self) -> std::io::Result<Vec<StoredChunk>> {
+        self.list_local(is_init_segment).await
+    }
+
+    async fn list_local(&self, include: fn(&Path) -> bool) -> std::io::Result<Vec<StoredChunk>> {
+        let mut chunks = Vec::new();
+
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(chunks),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() || !include(&entry.path()) {
+                continue;
+            }
+
+            chunks.push(StoredChunk {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                size: metadata.len() as usize,
+                last_modified: metadata.modified()?.into(),
+            });
+        }
+
+        Ok(chunks)
+    }
+
+    /// Remove a chunk from the local directory only, leaving the remote copy alone.
+    pub async fn delete_local_chunk(&self, name: &str) -> std::io::Result<()> {
+        let Some(path) = self.local_path(name) else {
+            return Ok(());
+        };
+
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Path of the chunk in the local directory, if `name` is a plain file name that can't escape it.
+    fn local_path(&self, name: &str) -> Option<PathBuf> {
+        let file_name = Path::new(name).file_name()?;
+        if file_name != name {
+            return None;
+        }
+
+        Some(self.directory.join(file_name))
+    }
+}
+
+impl<U: Uploader> Uploader for TieredUploader<U> {
+    async fn upload_chunk(&self, name: &str, chunk: Vec<u8>) -> anyhow::Result<()> {
+        self.remote.upload_chunk(name, chunk).await
+    }
+
+    async fn read_chunk(&self, name: &str) -> Result<Vec<u8>, ReadError> {
+        if let Some(path) = self.local_path(name) {
+            match tokio::fs::read(&path).await {
+                Ok(data) => {
+                    debug!(name = name, "Reading chunk from local disk");
+                    return Ok(data);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(error = %e, name = name, "Failed to read local chunk, trying remote");
+                }
+            }
+        }
+
+        self.remote.read_chunk(name).await
+    }
+
+    async fn chunk_size(&self, name: &str) -> Result<usize, ReadError> {
+        if let Some(path) = self.local_path(name) {
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) => return Ok(metadata.len() as usize),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(error = %e, name = name, "Failed to stat local chunk, trying remote");
+                }
+            }
+        }
+
+        self.remote.chunk_size(name).await
+    }
+
+    async fn read_chunk_range(
+        &self,
+        name: &str,
+        range: Range<usize>,
+    ) -> Result<Vec<u8>, ReadError> {
+        if let Some(path) = self.local_path(name) {
+            match read_file_range(&path, range.clone()).await {
+                Ok(data) => {
+                    debug!(name = name, "Reading chunk range {range:?} from local disk");
+                    return Ok(data);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(error = %e, name = name, "Failed to read local chunk, trying remote");
+                }
+            }
+        }
+
+        self.remote.read_chunk_range(name, range).await
+    }
+
+    async fn list_chunks(&self) -> anyhow::Result<Vec<StoredChunk>> {
+        self.remote.list_chunks().await
+    }
+
+    async fn delete_chunk(&self, name: &str) -> anyhow::Result<()> {
+        self.delete_local_chunk(name).await?;
+        self.remote.delete_chunk(name).await
+    }
+}
+
+async fn read_file_range(path: &Path, range: Range<usize>) -> std::io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start as u64)).await?;
+
+    let mut data = vec![0; range.len()];
+    file.read_exact(&mut data).await?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use object_store::memory::InMemory;
+
+    use crate::test_util::{block_on, scratch_dir};
+    use crate::upload::s3::ObjectStoreUploader;
+    use crate::upload::tiered::TieredUploader;
+    use crate::upload::Uploader;
+
+    #[test]
+    pub fn test_local_before_remote() {
+        let scratch = scratch_dir();
+        let directory = scratch.path();
+        std::fs::write(directory.join("000000002.ts"), b"local").unwrap();
+
+        let remote = ObjectStoreUploader::new(Arc::new(InMemory::new()), "");
+        let uploader = TieredUploader::new(directory, remote);
+
+        block_on(async {
+            uploader
+                .upload_chunk("000000001.ts", b"remote".to_vec())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                uploader.read_chunk("000000001.ts").await.unwrap(),
+                b"remote"
+            );
+            assert_eq!(uploader.read_chunk("000000002.ts").await.unwrap(), b"local");
+
+            assert_eq!(uploader.chunk_size("000000001.ts").await.unwrap(), 6);
+            assert_eq!(uploader.chunk_size("000000002.ts").await.unwrap(), 5);
+            assert_eq!(
+                uploader
+                    .read_chunk_range("000000001.ts", 1..3)
+                    .await
+                    .unwrap(),
+                b"em"
+            );
+            assert_eq!(
+                uploader
+                    .read_chunk_range("000000002.ts", 1..3)
+                    .await
+                    .unwrap(),
+                b"oc"
+            );
+        });
+    }
+
+    #[test]
+    pub fn test_local_path_stays_in_directory() {
+        let remote = ObjectStoreUploader::new(Arc::new(InMemory::new()), "");
+        let uploader = TieredUploader::new("recordings", remote);
+
+        assert!(uploader.local_path("000000001.ts").is_some());
+        assert!(uploader.local_path("..").is_none());
+        assert!(uploader.local_path("../v0.db").is_none());
+    }
+}